@@ -0,0 +1,462 @@
+//! Generates typed bindings for every procedure described in `services.json`.
+//!
+//! The output is included by `src/services.rs`. Every service becomes a module
+//! containing a `RemoteClass` marker per class, a Rust enum per enumeration
+//! and a `ProcedureCall` per procedure.
+
+extern crate prost;
+#[macro_use]
+extern crate prost_derive;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[path = "src/doc.rs"]
+mod doc;
+#[path = "src/schema.rs"]
+#[allow(dead_code)]
+mod schema;
+
+use schema::type_::TypeCode;
+
+fn main() {
+    println!("cargo:rerun-if-changed=services.json");
+    println!("cargo:rerun-if-changed=src/schema.rs");
+    println!("cargo:rerun-if-changed=src/doc.rs");
+
+    let mut json = String::new();
+    File::open("services.json")
+        .and_then(|mut f| f.read_to_string(&mut json))
+        .expect("Failed to read services.json");
+    let services: schema::Services =
+        serde_json::from_str(&json).expect("Failed to parse services.json");
+
+    let code = generate(&services);
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("services.rs");
+    File::create(&out)
+        .and_then(|mut f| f.write_all(code.as_bytes()))
+        .expect("Failed to write generated services");
+}
+
+fn generate(services: &schema::Services) -> String {
+    let mut out = String::new();
+
+    for service in &services.services {
+        doc_comment(&mut out, "", &service.documentation);
+        writeln!(out, "pub mod {} {{", module_name(&service.name)).unwrap();
+        generate_service(&mut out, service);
+        writeln!(out, "}}\n").unwrap();
+    }
+
+    generate_exception(&mut out, services);
+
+    out
+}
+
+/// The crate wide `Exception`, which sorts server errors into the exception
+/// enums of the services that define them.
+fn generate_exception(out: &mut String, services: &schema::Services) {
+    let services = services
+        .services
+        .iter()
+        .filter(|s| !s.exceptions.is_empty())
+        .collect::<Vec<_>>();
+
+    writeln!(
+        out,
+        "/// An exception thrown by a procedure, typed by the service defining it.
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum Exception {{"
+    ).unwrap();
+    for service in &services {
+        writeln!(
+            out,
+            "    #[fail(display = \"{{}}\", _0)]
+    {}({}::Exception),",
+            camel_case(&module_name(&service.name)),
+            module_name(&service.name)
+        ).unwrap();
+    }
+    writeln!(
+        out,
+        "    /// An exception that isn't described in `services.json`.
+    #[fail(display = \"{{}}\", _0)]
+    Other(::schema::Error),
+}}
+
+impl From<::schema::Error> for Exception {{
+    fn from(error: ::schema::Error) -> Self {{"
+    ).unwrap();
+    for service in &services {
+        writeln!(
+            out,
+            "        let error = match {}::Exception::from_error(error) {{
+            Ok(e) => return Exception::{}(e),
+            Err(error) => error,
+        }};",
+            module_name(&service.name),
+            camel_case(&module_name(&service.name))
+        ).unwrap();
+    }
+    writeln!(
+        out,
+        "        Exception::Other(error)
+    }}
+}}"
+    ).unwrap();
+}
+
+fn generate_service(out: &mut String, service: &schema::Service) {
+    let mut type_names = HashSet::new();
+
+    for class in &service.classes {
+        type_names.insert(class.name.clone());
+        generate_class(out, &service.name, class);
+    }
+
+    for enumeration in &service.enumerations {
+        type_names.insert(enumeration.name.clone());
+        generate_enumeration(out, enumeration);
+    }
+
+    if !service.exceptions.is_empty() {
+        generate_service_exception(out, service);
+    }
+
+    for procedure in &service.procedures {
+        // A few procedures share their name with a type, e.g. RemoteTech.Comms
+        let mut name = camel_case(&procedure.name);
+        if type_names.contains(&name) {
+            name.push_str("Call");
+        }
+
+        generate_procedure(out, &service.name, &name, procedure);
+    }
+}
+
+fn generate_service_exception(out: &mut String, service: &schema::Service) {
+    writeln!(
+        out,
+        "    /// Exceptions thrown by the procedures of this service. Every variant
+    /// keeps the original error, including the server side stack trace.
+    #[derive(Debug, Clone, PartialEq, Fail)]
+    pub enum Exception {{"
+    ).unwrap();
+    for exception in &service.exceptions {
+        doc_comment(out, "        ", &exception.documentation);
+        writeln!(
+            out,
+            "        #[fail(display = \"{{}}\", _0)]
+        {}(::schema::Error),",
+            exception.name
+        ).unwrap();
+    }
+    writeln!(
+        out,
+        "    }}
+
+    impl Exception {{
+        /// Returns the error unchanged if it isn't an exception of this service.
+        pub fn from_error(error: ::schema::Error) -> Result<Self, ::schema::Error> {{
+            if error.service != \"{}\" {{
+                return Err(error);
+            }}",
+        service.name
+    ).unwrap();
+    for exception in &service.exceptions {
+        writeln!(
+            out,
+            "            if error.name == \"{name}\" {{
+                return Ok(Exception::{name}(error));
+            }}",
+            name = exception.name
+        ).unwrap();
+    }
+    writeln!(
+        out,
+        "            Err(error)
+        }}
+    }}
+"
+    ).unwrap();
+}
+
+fn generate_class(out: &mut String, service: &str, class: &schema::Class) {
+    doc_comment(out, "    ", &class.documentation);
+    writeln!(
+        out,
+        "    pub enum {name} {{}}
+
+    impl ::object::RemoteClass for {name} {{
+        const SERVICE: &'static str = \"{service}\";
+        const NAME: &'static str = \"{name}\";
+    }}
+",
+        service = service,
+        name = class.name
+    ).unwrap();
+}
+
+fn generate_enumeration(out: &mut String, enumeration: &schema::Enumeration) {
+    doc_comment(out, "    ", &enumeration.documentation);
+    writeln!(out, "    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]").unwrap();
+    writeln!(out, "    pub enum {} {{", enumeration.name).unwrap();
+    for value in &enumeration.values {
+        doc_comment(out, "        ", &value.documentation);
+        writeln!(out, "        {} = {},", value.name, value.value).unwrap();
+    }
+    writeln!(out, "    }}\n").unwrap();
+
+    writeln!(
+        out,
+        "    impl ::server::FromProcedureResult for {name} {{
+        type Error = ::prost::DecodeError;
+        fn try_from(value: Vec<u8>) -> Result<Self, ::prost::DecodeError> {{
+            match ::encoding::decode_sint32(&value)? {{",
+        name = enumeration.name
+    ).unwrap();
+    for value in &enumeration.values {
+        writeln!(
+            out,
+            "                {} => Ok({}::{}),",
+            value.value, enumeration.name, value.name
+        ).unwrap();
+    }
+    writeln!(
+        out,
+        "                v => Err(::prost::DecodeError::new(format!(\"invalid {0} {{}}\", v))),
+            }}
+        }}
+    }}
+
+    impl ::server::EncodeArgument for {0} {{
+        fn encode_argument(&self) -> Vec<u8> {{
+            ::encoding::encode_sint32(*self as i32)
+        }}
+    }}
+",
+        enumeration.name
+    ).unwrap();
+}
+
+fn generate_procedure(
+    out: &mut String,
+    service: &str,
+    name: &str,
+    procedure: &schema::Procedure,
+) {
+    doc_comment(out, "    ", &procedure.documentation);
+    writeln!(out, "    #[derive(Debug, Clone, PartialEq)]").unwrap();
+    if procedure.parameters.is_empty() {
+        writeln!(out, "    pub struct {};\n", name).unwrap();
+    } else {
+        writeln!(out, "    pub struct {} {{", name).unwrap();
+        for parameter in &procedure.parameters {
+            if let Some(doc) = doc::param(&procedure.documentation, &parameter.name) {
+                doc_lines(out, "        ", &doc);
+            }
+            writeln!(
+                out,
+                "        pub {}: {},",
+                field_name(&parameter.name),
+                rust_type(parameter_type(parameter))
+            ).unwrap();
+        }
+        writeln!(out, "    }}\n").unwrap();
+    }
+
+    let result = match procedure.return_type {
+        Some(ref t) if procedure.return_is_nullable => format!("Option<{}>", rust_type(t)),
+        Some(ref t) => rust_type(t),
+        None => "()".to_string(),
+    };
+    writeln!(
+        out,
+        "    impl ::server::ProcedureCall for {} {{
+        type Result = {};
+        type Error = ::server::SimpleResultError;
+    }}
+",
+        name, result
+    ).unwrap();
+
+    let arguments = procedure
+        .parameters
+        .iter()
+        .map(|p| format!("::server::EncodeArgument::encode_argument(&p.{})", field_name(&p.name)))
+        .collect::<Vec<_>>();
+    writeln!(
+        out,
+        "    impl From<{name}> for ::schema::ProcedureCall {{
+        fn from({binding}: {name}) -> Self {{
+            ::encoding::procedure_call(\"{service}\", \"{procedure}\", vec![{arguments}])
+        }}
+    }}
+",
+        name = name,
+        binding = if arguments.is_empty() { "_" } else { "p" },
+        service = service,
+        procedure = procedure.name,
+        arguments = arguments.join(", ")
+    ).unwrap();
+}
+
+fn parameter_type(parameter: &schema::Parameter) -> &schema::Type {
+    parameter
+        .type_
+        .as_ref()
+        .expect("Every parameter has a type")
+}
+
+const TYPE_CODES: [TypeCode; 21] = [
+    TypeCode::None,
+    TypeCode::Double,
+    TypeCode::Float,
+    TypeCode::Sint32,
+    TypeCode::Sint64,
+    TypeCode::Uint32,
+    TypeCode::Uint64,
+    TypeCode::Bool,
+    TypeCode::String,
+    TypeCode::Bytes,
+    TypeCode::Class,
+    TypeCode::Enumeration,
+    TypeCode::Event,
+    TypeCode::ProcedureCall,
+    TypeCode::Stream,
+    TypeCode::Status,
+    TypeCode::Services,
+    TypeCode::Tuple,
+    TypeCode::List,
+    TypeCode::Set,
+    TypeCode::Dictionary,
+];
+
+fn type_code(t: &schema::Type) -> TypeCode {
+    *TYPE_CODES
+        .iter()
+        .find(|&&c| c as i32 == t.code)
+        .unwrap_or_else(|| panic!("Unknown type code {}", t.code))
+}
+
+fn rust_type(t: &schema::Type) -> String {
+    match type_code(t) {
+        TypeCode::None => "()".to_string(),
+        TypeCode::Double => "f64".to_string(),
+        TypeCode::Float => "f32".to_string(),
+        TypeCode::Sint32 => "i32".to_string(),
+        TypeCode::Sint64 => "i64".to_string(),
+        TypeCode::Uint32 => "u32".to_string(),
+        TypeCode::Uint64 => "u64".to_string(),
+        TypeCode::Bool => "bool".to_string(),
+        TypeCode::String => "String".to_string(),
+        TypeCode::Bytes => "Vec<u8>".to_string(),
+        TypeCode::Class => format!(
+            "::object::RemoteObject<super::{}::{}>",
+            module_name(&t.service),
+            t.name
+        ),
+        TypeCode::Enumeration => format!("super::{}::{}", module_name(&t.service), t.name),
+        TypeCode::Event => "::schema::Event".to_string(),
+        TypeCode::ProcedureCall => "::schema::ProcedureCall".to_string(),
+        TypeCode::Stream => "::schema::Stream".to_string(),
+        TypeCode::Status => "::schema::Status".to_string(),
+        TypeCode::Services => "::schema::Services".to_string(),
+        TypeCode::Tuple => {
+            let items = t.types.iter().map(rust_type).collect::<Vec<_>>();
+            if items.len() == 1 {
+                format!("({},)", items[0])
+            } else {
+                format!("({})", items.join(", "))
+            }
+        }
+        TypeCode::List => format!("Vec<{}>", rust_type(element_type(t, 0))),
+        TypeCode::Set => format!(
+            "::std::collections::HashSet<{}>",
+            rust_type(element_type(t, 0))
+        ),
+        TypeCode::Dictionary => format!(
+            "::std::collections::HashMap<{}, {}>",
+            rust_type(element_type(t, 0)),
+            rust_type(element_type(t, 1))
+        ),
+    }
+}
+
+fn element_type(t: &schema::Type, index: usize) -> &schema::Type {
+    t.types
+        .get(index)
+        .unwrap_or_else(|| panic!("Collection type {} is missing element types", t.code))
+}
+
+/// `SpaceCenter` -> `space_center`, `KRPC` -> `krpc`
+fn module_name(service: &str) -> String {
+    snake_case(service)
+}
+
+/// Parameter names are camel case, some of them are Rust keywords.
+fn field_name(parameter: &str) -> String {
+    let name = snake_case(parameter);
+    match name.as_str() {
+        "type" | "ref" | "in" | "fn" | "mod" | "move" | "match" | "loop" | "box" | "where"
+        | "use" | "self" | "super" | "crate" | "impl" | "trait" | "struct" | "enum" => {
+            name + "_"
+        }
+        _ => name,
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut out = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let previous = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).map_or(false, |c| c.is_lowercase());
+            if previous.is_lowercase() || previous.is_numeric()
+                || (previous.is_uppercase() && next_is_lower)
+            {
+                out.push('_');
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+
+    out
+}
+
+/// `Vessel_get_Name` -> `VesselGetName`
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap();
+            first.to_uppercase().chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+fn doc_comment(out: &mut String, indent: &str, documentation: &str) {
+    doc_lines(out, indent, &doc::markdown(documentation));
+}
+
+fn doc_lines(out: &mut String, indent: &str, text: &str) {
+    for line in text.lines() {
+        if line.is_empty() {
+            writeln!(out, "{}///", indent).unwrap();
+        } else {
+            writeln!(out, "{}/// {}", indent, line).unwrap();
+        }
+    }
+}
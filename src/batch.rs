@@ -0,0 +1,204 @@
+use schema;
+use server::{FromProcedureResult, ProcedureCall};
+
+/// A set of procedure calls that is sent to the server as a single request.
+///
+/// Implemented for tuples of up to ten, possibly different, `ProcedureCall`s
+/// and for `Vec`s of calls of the same type. Every call gets its own result.
+pub trait Batch: 'static {
+    type Results;
+
+    fn into_calls(self) -> Vec<schema::ProcedureCall>;
+
+    /// Decodes the results, which are guaranteed to be exactly as many as
+    /// there were calls.
+    fn from_results(results: Vec<schema::ProcedureResult>) -> Self::Results;
+}
+
+fn convert<P: ProcedureCall>(result: schema::ProcedureResult) -> Result<P::Result, P::Error> {
+    if let Some(e) = result.error {
+        return Err(e.into());
+    }
+
+    Ok(P::Result::try_from(result.value)?)
+}
+
+impl<P: ProcedureCall> Batch for Vec<P> {
+    type Results = Vec<Result<P::Result, P::Error>>;
+
+    fn into_calls(self) -> Vec<schema::ProcedureCall> {
+        self.into_iter().map(Into::into).collect()
+    }
+
+    fn from_results(results: Vec<schema::ProcedureResult>) -> Self::Results {
+        results.into_iter().map(convert::<P>).collect()
+    }
+}
+
+macro_rules! tuple_batch {
+    ($($p:ident),+) => {
+        impl<$($p: ProcedureCall),+> Batch for ($($p,)+) {
+            type Results = ($(Result<$p::Result, $p::Error>,)+);
+
+            fn into_calls(self) -> Vec<schema::ProcedureCall> {
+                #[allow(non_snake_case)]
+                let ($($p,)+) = self;
+                vec![$($p.into()),+]
+            }
+
+            fn from_results(results: Vec<schema::ProcedureResult>) -> Self::Results {
+                let mut results = results.into_iter();
+                ($(convert::<$p>(results.next().expect("one result per call")),)+)
+            }
+        }
+    };
+}
+
+tuple_batch!(P0);
+tuple_batch!(P0, P1);
+tuple_batch!(P0, P1, P2);
+tuple_batch!(P0, P1, P2, P3);
+tuple_batch!(P0, P1, P2, P3, P4);
+tuple_batch!(P0, P1, P2, P3, P4, P5);
+tuple_batch!(P0, P1, P2, P3, P4, P5, P6);
+tuple_batch!(P0, P1, P2, P3, P4, P5, P6, P7);
+tuple_batch!(P0, P1, P2, P3, P4, P5, P6, P7, P8);
+tuple_batch!(P0, P1, P2, P3, P4, P5, P6, P7, P8, P9);
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use super::*;
+
+    use futures::prelude::*;
+
+    use connection::{ConnectionError, RpcConnection};
+    use encoding;
+    use server::{BatchError, Server, SimpleResultError};
+    use services::Exception;
+    use services::krpc;
+    use services::krpc::Exception::ArgumentException;
+
+    fn error(name: &str) -> schema::Error {
+        schema::Error {
+            service: "KRPC".to_string(),
+            name: name.to_string(),
+            description: String::new(),
+            stack_trace: String::new(),
+        }
+    }
+
+    fn status(version: &str) -> schema::Status {
+        schema::Status {
+            version: version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tuple_calls() {
+        let calls = (krpc::GetStatus, krpc::RemoveStream { id: 3 }).into_calls();
+
+        assert_eq!(
+            vec!["GetStatus", "RemoveStream"],
+            calls.iter().map(|c| c.procedure.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_tuple_results() {
+        let results = vec![
+            schema::ProcedureResult {
+                error: None,
+                value: encoding::encode_message(&status("0.4.8")),
+            },
+            schema::ProcedureResult {
+                error: Some(error("ArgumentException")),
+                value: Vec::new(),
+            },
+        ];
+
+        let (status_result, remove_result) =
+            <(krpc::GetStatus, krpc::RemoveStream)>::from_results(results);
+
+        assert_eq!("0.4.8", status_result.unwrap().version);
+        match remove_result {
+            Err(SimpleResultError::Server(Exception::Krpc(ArgumentException(e)))) => {
+                assert_eq!("KRPC", e.service)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invoke_batch() {
+        #[derive(Debug)]
+        struct MockConnection;
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                r: schema::Request,
+            ) -> Box<
+                Future<Item = (schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                let results = r.calls
+                    .iter()
+                    .enumerate()
+                    .map(|(i, _)| schema::ProcedureResult {
+                        error: None,
+                        value: encoding::encode_message(&status(&i.to_string())),
+                    })
+                    .collect();
+
+                Box::new(::futures::future::ok((
+                    schema::Response {
+                        error: None,
+                        results,
+                    },
+                    self,
+                )))
+            }
+        }
+
+        let server = Server::new(MockConnection);
+
+        let batch = vec![krpc::GetStatus, krpc::GetStatus, krpc::GetStatus];
+        let (results, _) = server.invoke_batch(batch).wait().unwrap();
+
+        let versions = results
+            .into_iter()
+            .map(|r| r.unwrap().version)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["0", "1", "2"], versions);
+    }
+
+    #[test]
+    fn test_invoke_batch_missing_results() {
+        #[derive(Debug)]
+        struct MockConnection;
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                _: schema::Request,
+            ) -> Box<
+                Future<Item = (schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                Box::new(::futures::future::ok((
+                    schema::Response {
+                        error: None,
+                        results: vec![Default::default()],
+                    },
+                    self,
+                )))
+            }
+        }
+
+        let server = Server::new(MockConnection);
+
+        match server.invoke_batch((krpc::GetStatus, krpc::GetStatus)).wait() {
+            Err(BatchError::MissingResults(_)) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
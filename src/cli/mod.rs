@@ -0,0 +1,298 @@
+//! The `kai` command-line tool, for poking at the game without writing a
+//! client first.
+
+mod repl;
+mod text;
+
+use std::fs::File;
+
+use failure;
+use serde_json;
+
+use connection::ConnectionBuilder;
+use discovery::ServiceTree;
+use encoding;
+use schema;
+use services::{krpc, Exception};
+use sync::SyncClient;
+use value;
+
+use self::text::ValueError;
+
+const USAGE: &str = "Usage: kai [OPTIONS] call SERVICE PROCEDURE [ARGUMENT...]
+       kai [OPTIONS] repl
+
+`call` calls a procedure and prints its result, `repl` starts a prompt for
+calls with completion and documentation. Arguments are parsed by the types
+of the procedure's parameters: numbers, true or false, strings and base64
+bytes as they are, objects by handle, enumeration values by name and
+collections as JSON, e.g. '[1, 2]'. Omitted arguments take their defaults.
+
+Options:
+    --address HOST:PORT  The RPC port of the server [127.0.0.1:50000]
+    --name NAME          The client name shown in the game
+    --services FILE      Read the services from FILE, e.g. services.json,
+                         instead of asking the server";
+
+#[derive(Debug, Fail)]
+pub enum CliError {
+    #[fail(display = "{}", _0)]
+    Usage(String),
+    #[fail(display = "Unknown procedure {}.{}", _0, _1)]
+    UnknownProcedure(String, String),
+    #[fail(display = "Missing argument `{}` of type {}", _0, _1)]
+    MissingArgument(String, String),
+    #[fail(display = "Too many arguments, the procedure takes {}", _0)]
+    TooManyArguments(usize),
+    #[fail(display = "Invalid argument `{}`: {}", _0, _1)]
+    InvalidArgument(String, ValueError),
+    #[fail(display = "No result for procedure call")]
+    NoResult,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    address: String,
+    name: Option<String>,
+    services: Option<String>,
+    command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Call {
+        service: String,
+        procedure: String,
+        arguments: Vec<String>,
+    },
+    Repl,
+}
+
+/// Runs the tool with the command-line arguments `args`, without the name of
+/// the program.
+pub fn run(args: Vec<String>) -> Result<(), failure::Error> {
+    let options = parse_options(args)?;
+
+    let mut builder = ConnectionBuilder::new();
+    if let Some(name) = options.name {
+        builder = builder.client_name(name);
+    }
+    let mut client = SyncClient::connect_with(builder, &options.address[..])?;
+
+    let services = ServiceTree::new(match options.services {
+        Some(path) => serde_json::from_reader(File::open(path)?)?,
+        None => client.call(krpc::GetServices)?,
+    });
+
+    match options.command {
+        Command::Call {
+            service,
+            procedure,
+            arguments,
+        } => {
+            if let Some((_, result)) =
+                call(&mut client, &services, &service, &procedure, &arguments)?
+            {
+                println!("{}", result);
+            }
+            Ok(())
+        }
+        Command::Repl => repl::run(client, services),
+    }
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, CliError> {
+    let mut address = "127.0.0.1:50000".to_string();
+    let mut name = None;
+    let mut services = None;
+
+    let mut args = args.into_iter();
+    let command = loop {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => return Err(usage("Missing command")),
+        };
+
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| usage(&format!("Missing value of {}", arg)))
+        };
+        match &arg[..] {
+            "--address" => address = value()?,
+            "--name" => name = Some(value()?),
+            "--services" => services = Some(value()?),
+            "call" => break "call",
+            "repl" => break "repl",
+            _ => return Err(usage(&format!("Unknown argument {}", arg))),
+        }
+    };
+
+    let command = match (command, args.next(), args.next()) {
+        ("repl", None, _) => Command::Repl,
+        ("repl", Some(arg), _) => return Err(usage(&format!("Unknown argument {}", arg))),
+        (_, Some(service), Some(procedure)) => Command::Call {
+            service,
+            procedure,
+            arguments: args.collect(),
+        },
+        _ => return Err(usage("Missing procedure")),
+    };
+
+    Ok(Options {
+        address,
+        name,
+        services,
+        command,
+    })
+}
+
+fn usage(message: &str) -> CliError {
+    CliError::Usage(format!("{}\n\n{}", message, USAGE))
+}
+
+/// Calls `service.procedure` with arguments given as text. Returns the type
+/// and text of the result, if the procedure has one.
+fn call<'a>(
+    client: &mut SyncClient,
+    services: &'a ServiceTree,
+    service: &str,
+    procedure: &str,
+    arguments: &[String],
+) -> Result<Option<(&'a schema::Type, String)>, failure::Error> {
+    let definition = services
+        .procedure(service, procedure)
+        .ok_or_else(|| CliError::UnknownProcedure(service.to_string(), procedure.to_string()))?;
+    let arguments = encode_arguments(services, definition, arguments)?;
+
+    let request = schema::Request {
+        calls: vec![encoding::procedure_call(service, procedure, arguments)],
+    };
+    let response = client.request(request)?;
+    if let Some(e) = response.error {
+        return Err(Exception::from(e).into());
+    }
+
+    let result = response.results.into_iter().next().ok_or(CliError::NoResult)?;
+    if let Some(e) = result.error {
+        return Err(Exception::from(e).into());
+    }
+
+    match definition.return_type {
+        Some(ref t) => Ok(Some((t, text::format_result(services, t, result.value)?))),
+        None => Ok(None),
+    }
+}
+
+/// Parses one argument per parameter of `procedure`. Parameters past the
+/// given arguments take their default value.
+fn encode_arguments(
+    services: &ServiceTree,
+    procedure: &schema::Procedure,
+    arguments: &[String],
+) -> Result<Vec<Vec<u8>>, CliError> {
+    if arguments.len() > procedure.parameters.len() {
+        return Err(CliError::TooManyArguments(procedure.parameters.len()));
+    }
+
+    let mut encoded = Vec::new();
+    for (i, parameter) in procedure.parameters.iter().enumerate() {
+        let t = parameter.type_.clone().unwrap_or_default();
+
+        let value = match arguments.get(i) {
+            Some(argument) => text::parse_argument(services, &t, argument)
+                .map_err(|e| CliError::InvalidArgument(parameter.name.clone(), e))?,
+            // Only optional parameters have a default value
+            None if !parameter.default_value.is_empty() => parameter.default_value.clone(),
+            None => {
+                return Err(CliError::MissingArgument(
+                    parameter.name.clone(),
+                    value::type_name(&t),
+                ))
+            }
+        };
+        encoded.push(value);
+    }
+
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use schema::type_::TypeCode;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse_options(args(&[
+            "--address",
+            "10.0.0.2:1000",
+            "call",
+            "SpaceCenter",
+            "Flight_get_MeanAltitude",
+            "42",
+        ])).unwrap();
+
+        assert_eq!("10.0.0.2:1000", options.address);
+        assert_eq!(None, options.services);
+        assert_eq!(
+            Command::Call {
+                service: "SpaceCenter".to_string(),
+                procedure: "Flight_get_MeanAltitude".to_string(),
+                arguments: args(&["42"]),
+            },
+            options.command
+        );
+
+        match parse_options(args(&["call", "SpaceCenter"])) {
+            Err(CliError::Usage(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(Command::Repl, parse_options(args(&["repl"])).unwrap().command);
+        match parse_options(args(&["repl", "SpaceCenter"])) {
+            Err(CliError::Usage(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match parse_options(args(&["--name"])) {
+            Err(CliError::Usage(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_default_arguments() {
+        let parameter = |name: &str, default_value: Vec<u8>| schema::Parameter {
+            name: name.to_string(),
+            type_: Some(schema::Type {
+                code: TypeCode::Float as i32,
+                ..Default::default()
+            }),
+            default_value,
+        };
+        let procedure = schema::Procedure {
+            name: "Message".to_string(),
+            parameters: vec![
+                parameter("size", Vec::new()),
+                parameter("duration", encoding::encode_float(1.0)),
+            ],
+            ..Default::default()
+        };
+        let services = ServiceTree::default();
+
+        assert_eq!(
+            vec![encoding::encode_float(2.0), encoding::encode_float(1.0)],
+            encode_arguments(&services, &procedure, &args(&["2"])).unwrap()
+        );
+        match encode_arguments(&services, &procedure, &[]) {
+            Err(CliError::MissingArgument(ref name, _)) if name == "size" => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match encode_arguments(&services, &procedure, &args(&["1", "2", "3"])) {
+            Err(CliError::TooManyArguments(2)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! `kai repl`, a prompt for exploring the services of a server.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::mem;
+use std::rc::Rc;
+
+use failure;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::{self, Editor};
+
+use discovery::ServiceTree;
+use doc;
+use schema::{self, type_::TypeCode};
+use sync::SyncClient;
+use value;
+
+use super::{call, text};
+
+const HELP: &str = "Commands:
+    SERVICE.PROCEDURE [ARGUMENT...]         Call a procedure and print its result
+    NAME = SERVICE.PROCEDURE [ARGUMENT...]  Call it and keep the result as $NAME
+    help [SERVICE[.NAME]]                   Show the documentation
+    vars                                    List the kept results
+    exit                                    Leave, like Ctrl-D
+
+Arguments are separated by spaces, strings containing spaces go in quotes.
+An argument $NAME is replaced by the result kept as NAME. Tab completes
+names, enumeration values and fitting variables.";
+
+#[derive(Debug, Fail)]
+enum ReplError {
+    #[fail(display = "Unknown command {}, try `help`", _0)]
+    UnknownCommand(String),
+    #[fail(display = "Nothing is called {}", _0)]
+    UnknownName(String),
+    #[fail(display = "Unknown variable ${}", _0)]
+    UnknownVariable(String),
+    #[fail(display = "Invalid variable name {}", _0)]
+    InvalidVariableName(String),
+    #[fail(display = "{} returns nothing to keep", _0)]
+    NothingToKeep(String),
+}
+
+/// A result kept for later calls. Mostly object handles.
+#[derive(Debug, Clone, PartialEq)]
+struct Variable {
+    t: schema::Type,
+    text: String,
+}
+
+type Variables = Rc<RefCell<BTreeMap<String, Variable>>>;
+
+pub fn run(mut client: SyncClient, services: ServiceTree) -> Result<(), failure::Error> {
+    let services = Rc::new(services);
+    let variables = Variables::default();
+
+    let mut editor = Editor::<Completion>::new();
+    editor.set_completer(Some(Completion {
+        services: services.clone(),
+        variables: variables.clone(),
+    }));
+
+    println!("Connected, type `help` for a list of commands.");
+    loop {
+        let line = match editor.readline("kai> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        editor.add_history_entry(line.as_str());
+
+        match execute(&mut client, &services, &variables, &line) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            // A failed command doesn't end the session
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+}
+
+/// Executes one line. Returns false to end the session.
+fn execute(
+    client: &mut SyncClient,
+    services: &ServiceTree,
+    variables: &Variables,
+    line: &str,
+) -> Result<bool, failure::Error> {
+    let mut words = split(line);
+    let variable = assignment(&mut words);
+
+    let (command, arguments) = match words.split_first() {
+        Some(split) => split,
+        None => return Ok(true),
+    };
+
+    match &command[..] {
+        "exit" | "quit" if variable.is_none() => return Ok(false),
+        "help" if variable.is_none() => println!("{}", help(services, arguments.get(0))?),
+        "vars" if variable.is_none() => {
+            for (name, v) in variables.borrow().iter() {
+                println!("${}: {} = {}", name, value::type_name(&v.t), v.text);
+            }
+        }
+        _ => {
+            let (service, procedure) = match split_name(command) {
+                Some(name) => name,
+                None => return Err(ReplError::UnknownCommand(command.clone()).into()),
+            };
+            if let Some(ref name) = variable {
+                check_variable_name(name)?;
+                let returns = services
+                    .procedure(service, procedure)
+                    .map_or(true, |p| p.return_type.is_some());
+                if !returns {
+                    return Err(ReplError::NothingToKeep(command.clone()).into());
+                }
+            }
+
+            let arguments = arguments
+                .iter()
+                .map(|argument| substitute(&variables.borrow(), argument))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let result = call(client, services, service, procedure, &arguments)?;
+            match (result, variable) {
+                (Some((t, text)), Some(name)) => {
+                    println!("${} = {}", name, text);
+                    variables.borrow_mut().insert(name, Variable { t: t.clone(), text });
+                }
+                (Some((_, text)), None) => println!("{}", text),
+                (None, _) => {}
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Splits `line` into words at spaces. Quotes keep a string with spaces in
+/// one word, and so do brackets for collections in JSON.
+fn split(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // An empty pair of quotes is still a word
+    let mut quoted_word = false;
+
+    let mut quoted = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut depth = 0;
+
+    for c in line.chars() {
+        if escaped {
+            word.push(c);
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' if quoted || in_string => {
+                // JSON does its own unescaping
+                if in_string {
+                    word.push(c);
+                }
+                escaped = true;
+            }
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                quoted_word = true;
+            }
+            '"' => {
+                in_string = !in_string;
+                word.push(c);
+            }
+            '[' | '{' if !quoted && !in_string => {
+                depth += 1;
+                word.push(c);
+            }
+            ']' | '}' if !quoted && !in_string && depth > 0 => {
+                depth -= 1;
+                word.push(c);
+            }
+            c if c.is_whitespace() && !quoted && depth == 0 => {
+                if quoted_word || !word.is_empty() {
+                    words.push(mem::replace(&mut word, String::new()));
+                }
+                quoted_word = false;
+            }
+            c => word.push(c),
+        }
+    }
+    if quoted_word || !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Removes the `NAME =` in front of a call and returns the name.
+fn assignment(words: &mut Vec<String>) -> Option<String> {
+    if words.len() < 2 || words[1] != "=" {
+        return None;
+    }
+
+    let name = words.remove(0);
+    words.remove(0);
+    Some(name.trim_left_matches('$').to_string())
+}
+
+fn check_variable_name(name: &str) -> Result<(), ReplError> {
+    let mut chars = name.chars();
+    let valid = chars.next().map_or(false, |c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ReplError::InvalidVariableName(name.to_string()))
+    }
+}
+
+/// Replaces a `$NAME` argument with the kept result.
+fn substitute(variables: &BTreeMap<String, Variable>, argument: &str) -> Result<String, ReplError> {
+    if !argument.starts_with('$') {
+        return Ok(argument.to_string());
+    }
+
+    let name = &argument[1..];
+    variables
+        .get(name)
+        .map(|v| v.text.clone())
+        .ok_or_else(|| ReplError::UnknownVariable(name.to_string()))
+}
+
+/// `SpaceCenter.Vessel_get_Name` -> `("SpaceCenter", "Vessel_get_Name")`
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    let dot = name.find('.')?;
+    Some((&name[..dot], &name[dot + 1..]))
+}
+
+/// The documentation of a service or something in it.
+fn help(services: &ServiceTree, topic: Option<&String>) -> Result<String, ReplError> {
+    let mut out = String::new();
+
+    let topic = match topic {
+        Some(topic) => topic,
+        None => {
+            writeln!(out, "{}\n\nServices:", HELP).unwrap();
+            for service in services.services() {
+                writeln!(out, "    {}", service.name).unwrap();
+            }
+            return Ok(out.trim_right().to_string());
+        }
+    };
+
+    let (service, name) = split_name(topic).unwrap_or((topic, ""));
+    let service = services
+        .service(service)
+        .ok_or_else(|| ReplError::UnknownName(topic.clone()))?;
+
+    if name.is_empty() {
+        writeln!(out, "{}\n", doc::markdown(&service.documentation)).unwrap();
+        let classes = service.classes.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+        list(&mut out, "Classes", classes.iter().cloned());
+        list(
+            &mut out,
+            "Enumerations",
+            service.enumerations.iter().map(|e| &e.name[..]),
+        );
+        list(
+            &mut out,
+            "Procedures",
+            service
+                .procedures
+                .iter()
+                .map(|p| &p.name[..])
+                .filter(|p| !classes.iter().any(|c| p.starts_with(&format!("{}_", c)))),
+        );
+    } else if let Some(procedure) = service.procedures.iter().find(|p| p.name == name) {
+        writeln!(out, "{}\n", signature(service, procedure)).unwrap();
+        writeln!(out, "{}", doc::markdown(&procedure.documentation)).unwrap();
+        for parameter in &procedure.parameters {
+            if let Some(text) = doc::param(&procedure.documentation, &parameter.name) {
+                writeln!(out, "\n{}: {}", parameter.name, text).unwrap();
+            }
+        }
+    } else if let Some(class) = service.classes.iter().find(|c| c.name == name) {
+        writeln!(out, "{}\n", doc::markdown(&class.documentation)).unwrap();
+        let methods = services.methods(&service.name, &class.name);
+        list(&mut out, "Methods", methods.iter().map(|m| &m.procedure.name[..]));
+        let properties = services.properties(&service.name, &class.name);
+        list(
+            &mut out,
+            "Properties",
+            properties
+                .iter()
+                .flat_map(|p| p.getter.into_iter().chain(p.setter))
+                .map(|p| &p.name[..]),
+        );
+    } else if let Some(enumeration) = service.enumerations.iter().find(|e| e.name == name) {
+        writeln!(out, "{}\n\nValues:", doc::markdown(&enumeration.documentation)).unwrap();
+        for value in &enumeration.values {
+            let doc = doc::markdown(&value.documentation).replace('\n', " ");
+            writeln!(out, "    {} = {}  {}", value.name, value.value, doc).unwrap();
+        }
+    } else {
+        return Err(ReplError::UnknownName(topic.clone()));
+    }
+
+    Ok(out.trim().to_string())
+}
+
+fn list<'a, I: Iterator<Item = &'a str>>(out: &mut String, title: &str, names: I) {
+    let mut names = names.peekable();
+    if names.peek().is_none() {
+        return;
+    }
+
+    writeln!(out, "{}:", title).unwrap();
+    for name in names {
+        writeln!(out, "    {}", name).unwrap();
+    }
+    writeln!(out).unwrap();
+}
+
+/// E.g. `SpaceCenter.Vessel_get_Name(this: SpaceCenter.Vessel) -> String`.
+/// Optional parameters are in brackets.
+fn signature(service: &schema::Service, procedure: &schema::Procedure) -> String {
+    let parameters = procedure
+        .parameters
+        .iter()
+        .map(|p| {
+            let t = value::type_name(&p.type_.clone().unwrap_or_default());
+            if p.default_value.is_empty() {
+                format!("{}: {}", p.name, t)
+            } else {
+                format!("[{}: {}]", p.name, t)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut signature = format!(
+        "{}.{}({})",
+        service.name,
+        procedure.name,
+        parameters.join(", ")
+    );
+    if let Some(ref t) = procedure.return_type {
+        write!(signature, " -> {}", value::type_name(t)).unwrap();
+    }
+    signature
+}
+
+/// Completes the word under the cursor from the services and variables.
+struct Completion {
+    services: Rc<ServiceTree>,
+    variables: Variables,
+}
+
+impl Completion {
+    /// Commands, and the names in a service once it is followed by a dot.
+    fn names(&self, word: &str, types: bool) -> Vec<String> {
+        let service = match split_name(word) {
+            Some((service, _)) => service,
+            None => {
+                let commands = ["help", "vars", "exit"].iter().map(|c| c.to_string());
+                return commands
+                    .chain(self.services.services().iter().map(|s| format!("{}.", s.name)))
+                    .collect();
+            }
+        };
+
+        let service = match self.services.service(service) {
+            Some(service) => service,
+            None => return Vec::new(),
+        };
+
+        let mut names = service.procedures.iter().map(|p| &p.name).collect::<Vec<_>>();
+        if types {
+            names.extend(service.classes.iter().map(|c| &c.name));
+            names.extend(service.enumerations.iter().map(|e| &e.name));
+        }
+
+        names
+            .into_iter()
+            .map(|name| format!("{}.{}", service.name, name))
+            .collect()
+    }
+
+    /// Values for the argument at `index` of `command`: enumeration values,
+    /// booleans and variables of the parameter's type.
+    fn values(&self, command: &str, index: usize) -> Vec<String> {
+        let (service, procedure) = match split_name(command) {
+            Some(name) => name,
+            None => return Vec::new(),
+        };
+        let t = match self.services
+            .procedure(service, procedure)
+            .and_then(|p| p.parameters.get(index))
+            .and_then(|p| p.type_.as_ref())
+        {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+
+        let mut values = match TypeCode::from_i32(t.code) {
+            Some(TypeCode::Bool) => vec!["true".to_string(), "false".to_string()],
+            Some(TypeCode::Enumeration) => self.services
+                .enumeration(&t.service, &t.name)
+                .map(|e| e.values.iter().map(|v| v.name.clone()).collect())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        values.extend(
+            self.variables
+                .borrow()
+                .iter()
+                .filter(|&(_, v)| v.t == *t)
+                .map(|(name, _)| format!("${}", name)),
+        );
+
+        values
+    }
+}
+
+impl Completer for Completion {
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| c == ' ' || c == '\t')
+            .map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+
+        let mut words = split(&line[..start]);
+        assignment(&mut words);
+
+        let candidates = match words.split_first() {
+            None => self.names(word, false),
+            Some((command, _)) if command == "help" => self.names(word, true),
+            Some((command, arguments)) => self.values(command, arguments.len()),
+        };
+
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .filter(|c| c.starts_with(word))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn services() -> ServiceTree {
+        let class = |name: &str| schema::Type {
+            code: TypeCode::Class as i32,
+            service: "SpaceCenter".to_string(),
+            name: name.to_string(),
+            types: Vec::new(),
+        };
+
+        ServiceTree::new(schema::Services {
+            services: vec![schema::Service {
+                name: "SpaceCenter".to_string(),
+                procedures: vec![
+                    schema::Procedure {
+                        name: "get_ActiveVessel".to_string(),
+                        return_type: Some(class("Vessel")),
+                        ..Default::default()
+                    },
+                    schema::Procedure {
+                        name: "Vessel_Flight".to_string(),
+                        parameters: vec![
+                            schema::Parameter {
+                                name: "this".to_string(),
+                                type_: Some(class("Vessel")),
+                                default_value: Vec::new(),
+                            },
+                            schema::Parameter {
+                                name: "referenceFrame".to_string(),
+                                type_: Some(class("ReferenceFrame")),
+                                default_value: vec![0],
+                            },
+                        ],
+                        return_type: Some(class("Flight")),
+                        documentation: "<doc>\n<summary>\nFlight telemetry.\n</summary>\n\
+                                        <param name=\"referenceFrame\">\nDefaults to the \
+                                        surface.\n</param>\n</doc>"
+                            .to_string(),
+                        ..Default::default()
+                    },
+                    schema::Procedure {
+                        name: "Vessel_get_Name".to_string(),
+                        ..Default::default()
+                    },
+                ],
+                classes: vec![schema::Class {
+                    name: "Vessel".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    fn completion() -> Completion {
+        let variables = Variables::default();
+        variables.borrow_mut().insert(
+            "ship".to_string(),
+            Variable {
+                t: services().services()[0].procedures[0].return_type.clone().unwrap(),
+                text: "17".to_string(),
+            },
+        );
+
+        Completion {
+            services: Rc::new(services()),
+            variables,
+        }
+    }
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        completion().complete(line, line.len()).unwrap()
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            vec!["a", "=", "S.P", "Jeb's ship", "", "[1, \"a b\"]", "{\"k\": [2]}"],
+            split(r#"a = S.P  "Jeb's ship" "" [1, "a b"] {"k": [2]}"#)
+        );
+        assert_eq!(vec!["say \"hi\""], split(r#""say \"hi\"""#));
+    }
+
+    #[test]
+    fn test_complete_names() {
+        assert_eq!((0, vec!["SpaceCenter.".to_string()]), complete("Sp"));
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            (
+                4,
+                names(&["SpaceCenter.Vessel_Flight", "SpaceCenter.Vessel_get_Name"])
+            ),
+            complete("v = SpaceCenter.Vessel_")
+        );
+        // Classes only make sense for help
+        assert_eq!(
+            (
+                5,
+                names(&[
+                    "SpaceCenter.Vessel_Flight",
+                    "SpaceCenter.Vessel_get_Name",
+                    "SpaceCenter.Vessel",
+                ])
+            ),
+            complete("help SpaceCenter.Ves")
+        );
+    }
+
+    #[test]
+    fn test_complete_variables() {
+        assert_eq!(
+            (26, vec!["$ship".to_string()]),
+            complete("SpaceCenter.Vessel_Flight ")
+        );
+        assert_eq!((32, Vec::<String>::new()), complete("SpaceCenter.Vessel_Flight $ship "));
+    }
+
+    #[test]
+    fn test_substitute() {
+        let completion = completion();
+        let variables = completion.variables.borrow();
+
+        assert_eq!("17", substitute(&variables, "$ship").unwrap());
+        assert_eq!("ship", substitute(&variables, "ship").unwrap());
+        match substitute(&variables, "$plane") {
+            Err(ReplError::UnknownVariable(ref name)) if name == "plane" => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_help() {
+        let services = services();
+        let help = |topic: &str| help(&services, Some(&topic.to_string())).unwrap();
+
+        assert_eq!(
+            "SpaceCenter.Vessel_Flight(this: SpaceCenter.Vessel, \
+             [referenceFrame: SpaceCenter.ReferenceFrame]) -> SpaceCenter.Flight\n\n\
+             Flight telemetry.\n\n\
+             referenceFrame: Defaults to the surface.",
+            help("SpaceCenter.Vessel_Flight")
+        );
+        assert_eq!(
+            "Methods:\n    Vessel_Flight\n\nProperties:\n    Vessel_get_Name",
+            help("SpaceCenter.Vessel")
+        );
+        let service = help("SpaceCenter");
+        assert!(service.contains("Procedures:\n    get_ActiveVessel"));
+        assert!(!service.contains("    Vessel_get_Name"));
+    }
+}
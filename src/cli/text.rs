@@ -0,0 +1,350 @@
+//! Procedure arguments and results as text.
+//!
+//! Values are written the way they look in JSON, with a few shortcuts for
+//! arguments given on their own: strings and bytes (base64) need no quotes
+//! and enumeration values may be given by name. Objects are their handles,
+//! `null` being no object.
+
+use base64;
+use prost::DecodeError;
+use serde_json::{self, Map, Number, Value as Json};
+
+use discovery::ServiceTree;
+use schema::{self, type_::TypeCode};
+use value::{type_name, EncodeError, Value};
+
+#[derive(Debug, Fail)]
+pub enum ValueError {
+    #[fail(display = "expected {}, got {}", _0, _1)]
+    Expected(String, Json),
+    #[fail(display = "{} has no value {}", _0, _1)]
+    UnknownEnumValue(String, String),
+    #[fail(display = "values of type {} can't be given as text", _0)]
+    Unsupported(String),
+    #[fail(display = "{}", _0)]
+    Syntax(serde_json::Error),
+    #[fail(display = "{}", _0)]
+    Encode(EncodeError),
+}
+
+impl From<serde_json::Error> for ValueError {
+    fn from(e: serde_json::Error) -> Self {
+        ValueError::Syntax(e)
+    }
+}
+
+/// Parses an argument of type `t` and encodes it in kRPC's wire format.
+pub fn parse_argument(
+    services: &ServiceTree,
+    t: &schema::Type,
+    text: &str,
+) -> Result<Vec<u8>, ValueError> {
+    parse_value(services, t, text)?
+        .encode(t)
+        .map_err(ValueError::Encode)
+}
+
+fn parse_value(services: &ServiceTree, t: &schema::Type, text: &str) -> Result<Value, ValueError> {
+    let json = match TypeCode::from_i32(t.code) {
+        Some(TypeCode::String) | Some(TypeCode::Bytes) => Json::String(text.to_string()),
+        Some(TypeCode::Enumeration) if text.parse::<i32>().is_err() => {
+            Json::String(text.to_string())
+        }
+        _ => serde_json::from_str(text)?,
+    };
+
+    from_json(services, t, &json)
+}
+
+fn from_json(services: &ServiceTree, t: &schema::Type, json: &Json) -> Result<Value, ValueError> {
+    let expected = || ValueError::Expected(type_name(t), json.clone());
+
+    let code = match TypeCode::from_i32(t.code) {
+        Some(code) => code,
+        None => return Err(ValueError::Unsupported(type_name(t))),
+    };
+
+    let value = match code {
+        TypeCode::Double => Value::Double(json.as_f64().ok_or_else(&expected)?),
+        TypeCode::Float => Value::Float(json.as_f64().ok_or_else(&expected)? as f32),
+        TypeCode::Sint32 => {
+            let v = json.as_i64().ok_or_else(&expected)?;
+            if v < i32::min_value() as i64 || v > i32::max_value() as i64 {
+                return Err(expected());
+            }
+            Value::Sint32(v as i32)
+        }
+        TypeCode::Sint64 => Value::Sint64(json.as_i64().ok_or_else(&expected)?),
+        TypeCode::Uint32 => {
+            let v = json.as_u64().ok_or_else(&expected)?;
+            if v > u32::max_value() as u64 {
+                return Err(expected());
+            }
+            Value::Uint32(v as u32)
+        }
+        TypeCode::Uint64 => Value::Uint64(json.as_u64().ok_or_else(&expected)?),
+        TypeCode::Bool => Value::Bool(json.as_bool().ok_or_else(&expected)?),
+        TypeCode::String => Value::String(json.as_str().ok_or_else(&expected)?.to_string()),
+        TypeCode::Bytes => {
+            let text = json.as_str().ok_or_else(&expected)?;
+            Value::Bytes(base64::decode(text).map_err(|_| expected())?)
+        }
+        TypeCode::Class => match *json {
+            Json::Null => Value::Class(0),
+            _ => Value::Class(json.as_u64().ok_or_else(&expected)?),
+        },
+        TypeCode::Enumeration => {
+            let value = match *json {
+                Json::String(ref name) => services
+                    .enumeration(&t.service, &t.name)
+                    .and_then(|e| e.values.iter().find(|v| v.name == *name))
+                    .map(|v| v.value)
+                    .ok_or_else(|| ValueError::UnknownEnumValue(type_name(t), name.clone()))?,
+                _ => {
+                    let v = json.as_i64().ok_or_else(&expected)?;
+                    if v < i32::min_value() as i64 || v > i32::max_value() as i64 {
+                        return Err(expected());
+                    }
+                    v as i32
+                }
+            };
+            Value::Enumeration(value)
+        }
+        TypeCode::Tuple => {
+            let items = json.as_array().ok_or_else(&expected)?;
+            if items.len() != t.types.len() {
+                return Err(expected());
+            }
+            let items = t.types
+                .iter()
+                .zip(items)
+                .map(|(t, item)| from_json(services, t, item))
+                .collect::<Result<_, _>>()?;
+            Value::Tuple(items)
+        }
+        TypeCode::List | TypeCode::Set => {
+            let element = t.types.get(0).ok_or_else(&expected)?;
+            let items = json.as_array()
+                .ok_or_else(&expected)?
+                .iter()
+                .map(|item| from_json(services, element, item))
+                .collect::<Result<_, _>>()?;
+            if code == TypeCode::List {
+                Value::List(items)
+            } else {
+                Value::Set(items)
+            }
+        }
+        TypeCode::Dictionary => {
+            let (key, value) = match (t.types.get(0), t.types.get(1)) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(expected()),
+            };
+            // JSON keys are always strings, so they are parsed like arguments
+            let entries = json.as_object()
+                .ok_or_else(&expected)?
+                .iter()
+                .map(|(k, v)| {
+                    Ok((
+                        parse_value(services, key, k)?,
+                        from_json(services, value, v)?,
+                    ))
+                })
+                .collect::<Result<_, ValueError>>()?;
+            Value::Dictionary(entries)
+        }
+        TypeCode::None
+        | TypeCode::Event
+        | TypeCode::ProcedureCall
+        | TypeCode::Stream
+        | TypeCode::Status
+        | TypeCode::Services => return Err(ValueError::Unsupported(type_name(t))),
+    };
+
+    Ok(value)
+}
+
+/// Decodes a result of type `t` and writes it as text. Strings are written
+/// as they are, everything else as JSON.
+pub fn format_result(
+    services: &ServiceTree,
+    t: &schema::Type,
+    value: Vec<u8>,
+) -> Result<String, DecodeError> {
+    match to_json(services, t, Value::decode(t, &value)?) {
+        Json::String(s) => Ok(s),
+        json => Ok(serde_json::to_string_pretty(&json).expect("JSON values always serialize")),
+    }
+}
+
+/// `value` as JSON. `t` is only needed for the names of enumeration values.
+fn to_json(services: &ServiceTree, t: &schema::Type, value: Value) -> Json {
+    let none = schema::Type::default();
+    let element = |index: usize| t.types.get(index).unwrap_or(&none);
+
+    match value {
+        Value::None => Json::Null,
+        Value::Double(v) => float(v),
+        Value::Float(v) => float(v as f64),
+        Value::Sint32(v) => v.into(),
+        Value::Sint64(v) => v.into(),
+        Value::Uint32(v) => v.into(),
+        Value::Uint64(v) => v.into(),
+        Value::Bool(v) => v.into(),
+        Value::String(v) => v.into(),
+        Value::Bytes(v) => base64::encode(&v).into(),
+        Value::Class(0) => Json::Null,
+        Value::Class(handle) => handle.into(),
+        Value::Enumeration(v) => services
+            .enumeration(&t.service, &t.name)
+            .and_then(|e| e.values.iter().find(|value| value.value == v))
+            .map(|value| Json::String(value.name.clone()))
+            .unwrap_or_else(|| v.into()),
+        Value::Tuple(items) => Json::Array(
+            t.types
+                .iter()
+                .zip(items)
+                .map(|(t, item)| to_json(services, t, item))
+                .collect(),
+        ),
+        Value::List(items) | Value::Set(items) => Json::Array(
+            items
+                .into_iter()
+                .map(|item| to_json(services, element(0), item))
+                .collect(),
+        ),
+        Value::Dictionary(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                let key = match to_json(services, element(0), key) {
+                    Json::String(s) => s,
+                    json => json.to_string(),
+                };
+                map.insert(key, to_json(services, element(1), value));
+            }
+            Json::Object(map)
+        }
+        Value::Services(v) => serde_json::to_value(v).expect("Services always serialize"),
+        Value::Status(v) => format!("{:?}", v).into(),
+        Value::Stream(v) => format!("{:?}", v).into(),
+        Value::Event(v) => format!("{:?}", v).into(),
+        Value::ProcedureCall(v) => format!("{:?}", v).into(),
+    }
+}
+
+/// NaN and the infinities have no JSON representation.
+fn float(v: f64) -> Json {
+    Number::from_f64(v)
+        .map(Json::Number)
+        .unwrap_or_else(|| Json::String(v.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use encoding;
+
+    fn value_type(code: TypeCode) -> schema::Type {
+        schema::Type {
+            code: code as i32,
+            ..Default::default()
+        }
+    }
+
+    fn services() -> ServiceTree {
+        ServiceTree::new(schema::Services {
+            services: vec![schema::Service {
+                name: "SpaceCenter".to_string(),
+                enumerations: vec![schema::Enumeration {
+                    name: "SASMode".to_string(),
+                    values: vec![
+                        schema::EnumerationValue {
+                            name: "StabilityAssist".to_string(),
+                            value: 0,
+                            ..Default::default()
+                        },
+                        schema::EnumerationValue {
+                            name: "Prograde".to_string(),
+                            value: 3,
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    fn sas_mode() -> schema::Type {
+        schema::Type {
+            code: TypeCode::Enumeration as i32,
+            service: "SpaceCenter".to_string(),
+            name: "SASMode".to_string(),
+            types: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_values() {
+        let services = services();
+        let parse = |code, text| parse_argument(&services, &value_type(code), text).unwrap();
+
+        assert_eq!(encoding::encode_double(2.5), parse(TypeCode::Double, "2.5"));
+        assert_eq!(encoding::encode_sint32(-3), parse(TypeCode::Sint32, "-3"));
+        assert_eq!(encoding::encode_bool(true), parse(TypeCode::Bool, "true"));
+        assert_eq!(encoding::encode_string("Jeb's ship"), parse(TypeCode::String, "Jeb's ship"));
+        assert_eq!(encoding::encode_bytes(&[1, 2]), parse(TypeCode::Bytes, "AQI="));
+
+        assert_eq!(
+            encoding::encode_sint32(3),
+            parse_argument(&services, &sas_mode(), "Prograde").unwrap()
+        );
+        assert_eq!(
+            encoding::encode_sint32(3),
+            parse_argument(&services, &sas_mode(), "3").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let services = services();
+
+        match parse_argument(&services, &value_type(TypeCode::Uint32), "-1") {
+            Err(ValueError::Expected(..)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match parse_argument(&services, &sas_mode(), "Sideways") {
+            Err(ValueError::UnknownEnumValue(..)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match parse_argument(&services, &value_type(TypeCode::ProcedureCall), "{}") {
+            Err(ValueError::Unsupported(..)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_collections_roundtrip() {
+        let services = services();
+        let t = schema::Type {
+            code: TypeCode::Dictionary as i32,
+            types: vec![
+                sas_mode(),
+                schema::Type {
+                    code: TypeCode::Tuple as i32,
+                    types: vec![value_type(TypeCode::Float), value_type(TypeCode::Class)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let value = parse_argument(&services, &t, r#"{"Prograde": [0.5, null]}"#).unwrap();
+        assert_eq!(
+            "{\n  \"Prograde\": [\n    0.5,\n    null\n  ]\n}",
+            format_result(&services, &t, value).unwrap()
+        );
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use tokio::timer::Delay;
+
+use connection::{ConnectionError, RpcConnection};
+use discovery::{ProcedureIds, ServiceTree};
+use schema;
+use server::{FromProcedureResult, ProcedureCall};
+
+/// A cloneable handle for calling procedures from several tasks at once.
+///
+/// The connection itself is owned by the `ClientTask` returned alongside the
+/// first handle. Requests are written in the order they are made without
+/// waiting for earlier responses, which kRPC answers in the same order.
+///
+/// Dropping the future of a call cancels it. If the request was already
+/// written, its response is read and thrown away once it arrives.
+#[derive(Debug, Clone)]
+pub struct Client {
+    calls: mpsc::UnboundedSender<PendingCall>,
+    timeout: Option<Duration>,
+    procedure_ids: Option<Arc<ProcedureIds>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct PendingCall {
+    request: schema::Request,
+    reply: oneshot::Sender<Result<schema::Response, ConnectionError>>,
+}
+
+impl Client {
+    /// Creates a client for `connection`. The returned task drives the
+    /// connection and has to be spawned; it finishes once every handle is
+    /// dropped and all outstanding calls are answered.
+    pub fn new<C>(connection: C) -> (Self, ClientTask<C>)
+    where
+        C: Sink<SinkItem = schema::Request, SinkError = ConnectionError>
+            + Stream<Item = schema::Response, Error = ConnectionError>,
+    {
+        let (sender, receiver) = mpsc::unbounded();
+        (
+            Client::from_sender(sender),
+            ClientTask::resume(connection, receiver),
+        )
+    }
+
+    /// A client for calls that are served by whoever owns the receiving end.
+    pub(crate) fn from_sender(calls: mpsc::UnboundedSender<PendingCall>) -> Self {
+        Client {
+            calls,
+            timeout: None,
+            procedure_ids: None,
+        }
+    }
+
+    /// Fails calls made through this handle, and the handles cloned from it,
+    /// with `ConnectionError::TimedOut` if there is no response after
+    /// `timeout`. Timeouts need a tokio runtime.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends calls made through this handle, and the handles cloned from it,
+    /// with the ids of their procedures in `services` instead of their names.
+    /// Procedures that are not in `services` are still called by name.
+    pub fn resolve_calls(mut self, services: &ServiceTree) -> Self {
+        self.procedure_ids = Some(Arc::new(ProcedureIds::new(services)));
+        self
+    }
+
+    /// Sends `request` and resolves to the matching response.
+    pub fn request(
+        &self,
+        request: schema::Request,
+    ) -> impl Future<Item = schema::Response, Error = ConnectionError> + Send {
+        self.send(request, self.timeout)
+    }
+
+    /// Like `request`, with a timeout for just this call.
+    pub fn request_timeout(
+        &self,
+        request: schema::Request,
+        timeout: Duration,
+    ) -> impl Future<Item = schema::Response, Error = ConnectionError> + Send {
+        self.send(request, Some(timeout))
+    }
+
+    fn send(&self, mut request: schema::Request, timeout: Option<Duration>) -> ResponseFuture {
+        if let Some(ref ids) = self.procedure_ids {
+            ids.resolve_request(&mut request);
+        }
+
+        let (reply, response) = oneshot::channel();
+
+        // If the task is gone the reply sender is dropped with the call, which
+        // cancels `response`
+        let _ = self.calls.unbounded_send(PendingCall { request, reply });
+
+        ResponseFuture {
+            response,
+            deadline: timeout.map(|timeout| Delay::new(Instant::now() + timeout)),
+        }
+    }
+
+    pub fn invoke<P: ProcedureCall>(
+        &self,
+        p: P,
+    ) -> impl Future<Item = P::Result, Error = ClientError<P>> {
+        self.invoke_within(p, self.timeout)
+    }
+
+    /// Like `invoke`, with a timeout for just this call.
+    pub fn invoke_timeout<P: ProcedureCall>(
+        &self,
+        p: P,
+        timeout: Duration,
+    ) -> impl Future<Item = P::Result, Error = ClientError<P>> {
+        self.invoke_within(p, Some(timeout))
+    }
+
+    fn invoke_within<P: ProcedureCall>(
+        &self,
+        p: P,
+        timeout: Option<Duration>,
+    ) -> impl Future<Item = P::Result, Error = ClientError<P>> {
+        let request = schema::Request {
+            calls: vec![p.into()],
+        };
+
+        self.send(request, timeout)
+            .map_err(ClientError::Connection)
+            .and_then(|response| decode_result(response, 0))
+    }
+}
+
+/// Decodes the result of the call at `index` in the request of `response`.
+pub(crate) fn decode_result<P: ProcedureCall>(
+    response: schema::Response,
+    index: usize,
+) -> Result<P::Result, ClientError<P>> {
+    if let Some(e) = response.error {
+        return Err(ClientError::Request(e));
+    }
+
+    let result = match response.results.into_iter().nth(index) {
+        Some(result) => result,
+        None => return Err(ClientError::NoResult),
+    };
+
+    if let Some(e) = result.error {
+        return Err(ClientError::Procedure(e.into()));
+    }
+
+    P::Result::try_from(result.value).map_err(ClientError::Decode)
+}
+
+/// Lets a `Server` share the connection of a `Client`.
+impl RpcConnection for Client {
+    fn call(
+        self,
+        r: schema::Request,
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+        Box::new(self.request(r).map(move |response| (response, self)))
+    }
+
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        timeout: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send> {
+        Box::new(
+            self.request_timeout(r, timeout)
+                .then(move |result| match result {
+                    Ok(response) => Ok((Some(response), self)),
+                    // The task skips the response once it arrives
+                    Err(ConnectionError::TimedOut) => Ok((None, self)),
+                    Err(e) => Err(e),
+                }),
+        )
+    }
+}
+
+/// The response to a call made through a `Client`.
+#[derive(Debug)]
+struct ResponseFuture {
+    response: oneshot::Receiver<Result<schema::Response, ConnectionError>>,
+    deadline: Option<Delay>,
+}
+
+impl Future for ResponseFuture {
+    type Item = schema::Response;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<schema::Response>, ConnectionError> {
+        match self.response.poll() {
+            Ok(Async::Ready(response)) => return response.map(Async::Ready),
+            Ok(Async::NotReady) => {}
+            Err(oneshot::Canceled) => return Err(ConnectionError::Closed),
+        }
+
+        if let Some(ref mut deadline) = self.deadline {
+            // Dropping `response` on the way out makes the task skip the
+            // response once it arrives
+            try_ready!(deadline.poll());
+            return Err(ConnectionError::TimedOut);
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Owns the connection of a `Client`, writing requests and handing the
+/// responses back in FIFO order.
+#[derive(Debug)]
+pub struct ClientTask<C> {
+    connection: C,
+    calls: mpsc::UnboundedReceiver<PendingCall>,
+    /// Calls made by the owner of the task, see `enqueue`.
+    queued: VecDeque<PendingCall>,
+    /// A call the connection wasn't ready to accept yet.
+    buffered: Option<PendingCall>,
+    in_flight: VecDeque<oneshot::Sender<Result<schema::Response, ConnectionError>>>,
+    /// Every handle is dropped, no more calls will arrive.
+    done: bool,
+}
+
+impl<C> ClientTask<C> {
+    /// Continues serving the calls of an earlier task on a new connection.
+    pub(crate) fn resume(connection: C, calls: mpsc::UnboundedReceiver<PendingCall>) -> Self {
+        ClientTask {
+            connection,
+            calls,
+            queued: VecDeque::new(),
+            buffered: None,
+            in_flight: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Fails the outstanding calls with `e` and gives up the connection. Calls
+    /// made afterwards wait in the returned queue.
+    pub(crate) fn into_calls(
+        mut self,
+        e: &ConnectionError,
+    ) -> mpsc::UnboundedReceiver<PendingCall> {
+        self.fail_all(e);
+        self.calls
+    }
+
+    /// Sends `request` without going through a `Client`, so it doesn't keep
+    /// the task alive. It is sent before the calls of the clients that are
+    /// still waiting.
+    pub(crate) fn enqueue(
+        &mut self,
+        request: schema::Request,
+    ) -> oneshot::Receiver<Result<schema::Response, ConnectionError>> {
+        let (reply, response) = oneshot::channel();
+        self.queued.push_back(PendingCall { request, reply });
+        response
+    }
+
+    fn fail_all(&mut self, e: &ConnectionError) {
+        let buffered = self.buffered.take().map(|call| call.reply);
+        let queued = self.queued.drain(..).map(|call| call.reply);
+        for reply in self.in_flight.drain(..).chain(buffered).chain(queued) {
+            let _ = reply.send(Err(e.duplicate()));
+        }
+    }
+}
+
+impl<C> ClientTask<C>
+where
+    C: Sink<SinkItem = schema::Request, SinkError = ConnectionError>
+        + Stream<Item = schema::Response, Error = ConnectionError>,
+{
+    fn send_calls(&mut self) -> Result<(), ConnectionError> {
+        loop {
+            if let Some(PendingCall { request, reply }) = self.buffered.take() {
+                if reply.is_canceled() {
+                    // Nobody waits for the response, don't bother the server
+                    continue;
+                }
+
+                match self.connection.start_send(request)? {
+                    AsyncSink::Ready => self.in_flight.push_back(reply),
+                    AsyncSink::NotReady(request) => {
+                        self.buffered = Some(PendingCall { request, reply });
+                        break;
+                    }
+                }
+            }
+
+            if let Some(call) = self.queued.pop_front() {
+                self.buffered = Some(call);
+                continue;
+            }
+
+            match self.calls.poll() {
+                Ok(Async::Ready(Some(call))) => self.buffered = Some(call),
+                Ok(Async::Ready(None)) | Err(()) => {
+                    self.done = true;
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        self.connection.poll_complete()?;
+        Ok(())
+    }
+
+    fn receive_responses(&mut self) -> Result<(), ConnectionError> {
+        while !self.in_flight.is_empty() {
+            let response = match self.connection.poll()? {
+                Async::Ready(Some(response)) => response,
+                Async::Ready(None) => return Err(ConnectionError::Closed),
+                Async::NotReady => break,
+            };
+
+            let reply = self.in_flight.pop_front().unwrap();
+            // The caller may have lost interest in the response
+            let _ = reply.send(Ok(response));
+        }
+
+        Ok(())
+    }
+}
+
+impl<C> Future for ClientTask<C>
+where
+    C: Sink<SinkItem = schema::Request, SinkError = ConnectionError>
+        + Stream<Item = schema::Response, Error = ConnectionError>,
+{
+    type Item = ();
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<()>, ConnectionError> {
+        if let Err(e) = self.send_calls().and_then(|_| self.receive_responses()) {
+            self.fail_all(&e);
+            return Err(e);
+        }
+
+        let idle = self.buffered.is_none() && self.queued.is_empty() && self.in_flight.is_empty();
+        if self.done && idle {
+            return Ok(Async::Ready(()));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// The error of a call made through a `Client`. Unlike `ProcedureCallError`,
+/// the client stays usable and isn't part of the error.
+#[derive(Debug)]
+pub enum ClientError<P: ProcedureCall> {
+    Connection(ConnectionError),
+    Procedure(P::Error),
+    NoResult,
+    Request(schema::Error),
+    Decode(<P::Result as FromProcedureResult>::Error),
+}
+
+impl<P: ProcedureCall> Display for ClientError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ClientError::Connection(ref e) => write!(f, "Connection Error: {}", e),
+            ClientError::Procedure(ref e) => write!(f, "Procedure Error: {}", e),
+            ClientError::NoResult => write!(f, "No result for procedure call"),
+            ClientError::Request(ref e) => write!(f, "Request Error: {}", e),
+            ClientError::Decode(ref e) => write!(f, "Decode Error: {:?}", e),
+        }
+    }
+}
+
+impl<P> ::failure::Fail for ClientError<P>
+where
+    P: ProcedureCall + Debug,
+    P::Result: Debug,
+    <P::Result as FromProcedureResult>::Error: Debug + Send + Sync,
+{
+    fn cause(&self) -> Option<&::failure::Fail> {
+        match *self {
+            ClientError::Connection(ref e) => Some(e),
+            ClientError::Procedure(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use futures::future;
+
+    use encoding;
+    use services::krpc;
+
+    /// Answers every request with the procedure names of its calls, unless it
+    /// is told to hold them back.
+    #[derive(Debug, Default)]
+    struct MockConnection {
+        requests: VecDeque<schema::Request>,
+        closed: bool,
+        held: Arc<AtomicBool>,
+    }
+
+    impl Sink for MockConnection {
+        type SinkItem = schema::Request;
+        type SinkError = ConnectionError;
+
+        fn start_send(
+            &mut self,
+            item: schema::Request,
+        ) -> Result<AsyncSink<schema::Request>, ConnectionError> {
+            self.requests.push_back(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Result<Async<()>, ConnectionError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl Stream for MockConnection {
+        type Item = schema::Response;
+        type Error = ConnectionError;
+
+        fn poll(&mut self) -> Result<Async<Option<schema::Response>>, ConnectionError> {
+            if self.closed {
+                return Ok(Async::Ready(None));
+            }
+            if self.held.load(Ordering::SeqCst) {
+                return Ok(Async::NotReady);
+            }
+
+            let request = match self.requests.pop_front() {
+                Some(request) => request,
+                None => return Ok(Async::NotReady),
+            };
+
+            let results = request
+                .calls
+                .iter()
+                .map(|call| schema::ProcedureResult {
+                    error: None,
+                    value: encoding::encode_string(&call.procedure),
+                })
+                .collect();
+
+            Ok(Async::Ready(Some(schema::Response {
+                error: None,
+                results,
+            })))
+        }
+    }
+
+    fn request(procedure: &str) -> schema::Request {
+        schema::Request {
+            calls: vec![encoding::procedure_call("KRPC", procedure, Vec::new())],
+        }
+    }
+
+    #[test]
+    fn test_pipelined_calls_fifo() {
+        let (client, task) = Client::new(MockConnection::default());
+
+        let calls = future::join_all(
+            ["GetClientID", "GetStatus", "GetServices"]
+                .iter()
+                .map(|procedure| client.request(request(procedure)))
+                .collect::<Vec<_>>(),
+        );
+        drop(client);
+
+        let (_, responses) = task.join(calls).wait().unwrap();
+
+        let procedures = responses
+            .into_iter()
+            .map(|r| encoding::decode_string(&r.results[0].value).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["GetClientID", "GetStatus", "GetServices"], procedures);
+    }
+
+    #[test]
+    fn test_connection_error_fails_pending_calls() {
+        let (client, task) = Client::new(MockConnection {
+            closed: true,
+            ..Default::default()
+        });
+
+        let call = client.request(request("GetStatus"));
+        let status = client.invoke(krpc::GetStatus);
+
+        assert!(task.wait().is_err());
+        match call.wait() {
+            Err(ConnectionError::Closed) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match status.wait() {
+            Err(ClientError::Connection(ConnectionError::Closed)) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        // The task is gone
+        match client.request(request("GetStatus")).wait() {
+            Err(ConnectionError::Closed) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    fn procedure(response: schema::Response) -> String {
+        encoding::decode_string(&response.results[0].value).unwrap()
+    }
+
+    #[test]
+    fn test_canceled_call_is_not_sent() {
+        let (client, task) = Client::new(MockConnection::default());
+
+        drop(client.request(request("GetClientID")));
+        let status = client.request(request("GetStatus"));
+        drop(client);
+
+        let (_, response) = task.join(status).wait().unwrap();
+        assert_eq!("GetStatus", procedure(response));
+    }
+
+    #[test]
+    fn test_late_response_is_dropped() {
+        let held = Arc::new(AtomicBool::new(true));
+        let (client, mut task) = Client::new(MockConnection {
+            held: held.clone(),
+            ..Default::default()
+        });
+
+        let timed_out = client.request(request("GetClientID"));
+        let status = client.request(request("GetStatus"));
+        drop(client);
+
+        // Both requests are written, but not answered yet
+        let polled = future::poll_fn(|| task.poll().map(Async::Ready)).wait();
+        assert_eq!(Async::NotReady, polled.unwrap());
+
+        drop(timed_out);
+        held.store(false, Ordering::SeqCst);
+
+        let (_, response) = task.join(status).wait().unwrap();
+        assert_eq!("GetStatus", procedure(response));
+    }
+}
@@ -0,0 +1,166 @@
+use std::io::{self, Read, Write};
+
+use bytes::BytesMut;
+use prost::Message;
+use tokio_io::codec::{Decoder, Encoder};
+
+use super::codec::VarintFramedCodec;
+use super::{check_handshake, ConnectionBuilder, ConnectionError};
+use encoding;
+use schema;
+
+/// A connection that blocks the calling thread, for use without an event
+/// loop. Messages are framed like on a `TokioConnection`.
+#[derive(Debug)]
+pub(crate) struct BlockingConnection<T> {
+    io: T,
+    codec: VarintFramedCodec,
+    buffer: BytesMut,
+    handshake_response: schema::ConnectionResponse,
+}
+
+impl ConnectionBuilder {
+    /// The blocking counterpart of `initialize`.
+    pub(crate) fn initialize_blocking<T>(
+        self,
+        io: T,
+    ) -> Result<BlockingConnection<T>, ConnectionError>
+    where
+        T: Read + Write,
+    {
+        let codec = self.codec();
+        let request = schema::ConnectionRequest {
+            type_: schema::connection_request::Type::Rpc.into(),
+            client_name: self.client_name,
+            client_identifier: Vec::new(),
+        };
+
+        BlockingConnection::handshake(io, codec, &request)
+    }
+
+    /// The blocking counterpart of `initialize_stream`.
+    pub(crate) fn initialize_blocking_stream<T>(
+        self,
+        io: T,
+        client_identifier: Vec<u8>,
+    ) -> Result<BlockingConnection<T>, ConnectionError>
+    where
+        T: Read + Write,
+    {
+        let codec = self.codec();
+        let request = schema::ConnectionRequest {
+            type_: schema::connection_request::Type::Stream.into(),
+            client_name: String::new(),
+            client_identifier,
+        };
+
+        BlockingConnection::handshake(io, codec, &request)
+    }
+}
+
+impl<T: Read + Write> BlockingConnection<T> {
+    /// A connection without handshake, e.g. the server's end of one.
+    pub(crate) fn new(io: T) -> Self {
+        BlockingConnection {
+            io,
+            codec: VarintFramedCodec::default(),
+            buffer: BytesMut::new(),
+            handshake_response: Default::default(),
+        }
+    }
+
+    fn handshake(
+        io: T,
+        codec: VarintFramedCodec,
+        request: &schema::ConnectionRequest,
+    ) -> Result<Self, ConnectionError> {
+        let mut connection = BlockingConnection {
+            codec,
+            ..Self::new(io)
+        };
+
+        connection.send(request)?;
+        let response = connection.receive()?;
+        connection.handshake_response = check_handshake(response)?;
+
+        Ok(connection)
+    }
+
+    pub(crate) fn client_identifier(&self) -> &[u8] {
+        &self.handshake_response.client_identifier
+    }
+
+    pub(crate) fn send<M: Message>(&mut self, message: &M) -> Result<(), ConnectionError> {
+        let mut frame = BytesMut::new();
+        self.codec.encode(encoding::encode_message(message), &mut frame)?;
+
+        self.io.write_all(&frame)?;
+        self.io.flush()?;
+        Ok(())
+    }
+
+    /// Waits for the next message.
+    pub(crate) fn receive<M: Message + Default>(&mut self) -> Result<M, ConnectionError> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buffer)? {
+                return Ok(M::decode(frame)?);
+            }
+
+            let read = match self.io.read(&mut chunk) {
+                Ok(0) => return Err(ConnectionError::Closed),
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tests::MockIo;
+
+    #[test]
+    fn test_blocking_handshake_and_receive() {
+        let response = schema::Response {
+            error: None,
+            results: vec![schema::ProcedureResult {
+                error: None,
+                value: vec![42],
+            }],
+        };
+
+        let mut input = Vec::new();
+        schema::ConnectionResponse {
+            status: schema::connection_response::Status::Ok.into(),
+            message: String::new(),
+            client_identifier: vec![1, 2],
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+        response.encode_length_delimited(&mut input).unwrap();
+
+        let io = MockIo::new(input);
+        let written = io.written();
+
+        let mut connection = ConnectionBuilder::new()
+            .client_name("Script")
+            .initialize_blocking(io)
+            .unwrap();
+        assert_eq!(&[1, 2], connection.client_identifier());
+
+        let request =
+            schema::ConnectionRequest::decode_length_delimited(&written.lock().unwrap()[..])
+                .unwrap();
+        assert_eq!("Script", request.client_name);
+
+        assert_eq!(response, connection.receive().unwrap());
+        match connection.receive::<schema::Response>() {
+            Err(ConnectionError::Closed) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
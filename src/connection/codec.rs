@@ -1,24 +1,51 @@
-use std::io;
-
 use bytes::BytesMut;
 use tokio_io::codec;
 
 use super::varint::*;
+use super::ConnectionError;
+
+/// The limit of `VarintFramedCodec::default`. Large enough for the service
+/// definitions of a heavily modded game.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
 
-#[derive(Debug)]
-pub struct VarintFramedCodec;
+/// Frames messages with a varint length prefix. Messages longer than
+/// `max_message_size` are rejected in both directions, on receiving as soon as
+/// the length prefix is read.
+#[derive(Debug, Clone, Copy)]
+pub struct VarintFramedCodec {
+    max_message_size: usize,
+}
+
+impl VarintFramedCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        VarintFramedCodec { max_message_size }
+    }
+
+    fn check_len(&self, len: usize) -> Result<(), ConnectionError> {
+        // Longer messages can't be framed at all
+        let max = self.max_message_size.min(u32::max_value() as usize);
+        if len > max {
+            return Err(ConnectionError::MessageTooLong { len, max });
+        }
+        Ok(())
+    }
+}
+
+impl Default for VarintFramedCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
 
 impl codec::Encoder for VarintFramedCodec {
     type Item = Vec<u8>;
-    type Error = io::Error;
+    type Error = ConnectionError;
 
-    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<(), io::Error> {
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<(), ConnectionError> {
         use bytes::BufMut;
 
         let len = item.len();
-        if len > u32::max_value() as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "message too long"));
-        }
+        self.check_len(len)?;
         dst.reserve(len + 5);
         super::varint::encode_varint(dst, len as u32);
         dst.put(item);
@@ -28,16 +55,16 @@ impl codec::Encoder for VarintFramedCodec {
 
 impl codec::Decoder for VarintFramedCodec {
     type Item = BytesMut;
-    type Error = io::Error;
+    type Error = ConnectionError;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ConnectionError> {
         match try_decode_varint(&*src) {
             DecodedVarint::NotEnough => Ok(None),
-            DecodedVarint::Invalid => {
-                Err(io::Error::new(io::ErrorKind::Other, "Invalid frame length"))
-            }
+            DecodedVarint::Invalid => Err(ConnectionError::InvalidFrameLength),
             DecodedVarint::Ok { value, bytes } => {
                 let value = value as usize;
+                self.check_len(value)?;
+
                 let total_len = value + bytes;
                 if src.len() < total_len {
                     Ok(None)
@@ -63,7 +90,7 @@ mod test {
             |v| {
                 use tokio_io::codec::{Decoder, Encoder};
 
-                let mut codec = VarintFramedCodec;
+                let mut codec = VarintFramedCodec::default();
                 let mut buffer = BytesMut::new();
 
                 codec.encode(v.clone(), &mut buffer).unwrap();
@@ -84,4 +111,44 @@ mod test {
             file!(),
         ).unwrap();
     }
+
+    #[test]
+    fn test_oversized_length_prefix() {
+        run_test(
+            &(0u32..1024, 1u32..u32::max_value() - 1024),
+            |&(max, excess)| {
+                use tokio_io::codec::Decoder;
+
+                let mut codec = VarintFramedCodec::new(max as usize);
+                let mut buffer = BytesMut::with_capacity(5);
+                encode_varint(&mut buffer, max + excess);
+
+                // Rejected without waiting for the rest of the message
+                match codec.decode(&mut buffer) {
+                    Err(ConnectionError::MessageTooLong { len, max: limit }) => {
+                        assert_eq!((max + excess) as usize, len);
+                        assert_eq!(max as usize, limit);
+                    }
+                    other => panic!("Unexpected result: {:?}", other),
+                }
+
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_encode_too_long() {
+        use tokio_io::codec::Encoder;
+
+        let mut codec = VarintFramedCodec::new(3);
+        let mut buffer = BytesMut::new();
+
+        codec.encode(vec![1, 2, 3], &mut buffer).unwrap();
+        match codec.encode(vec![1, 2, 3, 4], &mut buffer) {
+            Err(ConnectionError::MessageTooLong { len: 4, max: 3 }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use prost::DecodeError;
+use tokio::timer;
+use tungstenite;
+
+use schema;
+use schema::connection_response::Status;
+
+/// Everything that can go wrong on a connection to the kRPC server.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The server refused the connection request.
+    Rejected { status: Status, message: String },
+    /// The varint length prefix of a received frame is malformed.
+    InvalidFrameLength,
+    /// A message is longer than the limit of the connection, in bytes.
+    MessageTooLong { len: usize, max: usize },
+    /// A received message is not valid protobuf.
+    Decode(DecodeError),
+    /// The server closed the connection.
+    Closed,
+    /// No response arrived within the timeout of the call.
+    TimedOut,
+    Io(io::Error),
+    /// Failure of the WebSocket transport, including a rejected handshake.
+    WebSocket(tungstenite::Error),
+    /// A `ReplayConnection` got another request than the recorded one.
+    /// `expected` is `None` past the end of the recording.
+    Diverged {
+        expected: Option<schema::Request>,
+        actual: schema::Request,
+    },
+}
+
+impl ConnectionError {
+    /// A copy of this error, for reporting one failure to several callers.
+    /// Wrapped errors that can't be cloned keep only their message.
+    pub(crate) fn duplicate(&self) -> Self {
+        match *self {
+            ConnectionError::Rejected {
+                status,
+                ref message,
+            } => ConnectionError::Rejected {
+                status,
+                message: message.clone(),
+            },
+            ConnectionError::InvalidFrameLength => ConnectionError::InvalidFrameLength,
+            ConnectionError::MessageTooLong { len, max } => {
+                ConnectionError::MessageTooLong { len, max }
+            }
+            ConnectionError::Decode(ref e) => {
+                ConnectionError::Decode(DecodeError::new(e.to_string()))
+            }
+            ConnectionError::Closed => ConnectionError::Closed,
+            ConnectionError::TimedOut => ConnectionError::TimedOut,
+            ConnectionError::Io(ref e) => {
+                ConnectionError::Io(io::Error::new(e.kind(), e.to_string()))
+            }
+            ConnectionError::WebSocket(ref e) => {
+                ConnectionError::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))
+            }
+            ConnectionError::Diverged {
+                ref expected,
+                ref actual,
+            } => ConnectionError::Diverged {
+                expected: expected.clone(),
+                actual: actual.clone(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConnectionError::Rejected {
+                status,
+                ref message,
+            } => write!(f, "Connection rejected ({:?}): {}", status, message),
+            ConnectionError::InvalidFrameLength => write!(f, "Invalid frame length"),
+            ConnectionError::MessageTooLong { len, max } => write!(
+                f,
+                "Message of {} bytes exceeds the limit of {} bytes",
+                len, max
+            ),
+            ConnectionError::Decode(ref e) => write!(f, "Invalid message: {}", e),
+            ConnectionError::Closed => write!(f, "Connection closed"),
+            ConnectionError::TimedOut => write!(f, "Timed out waiting for a response"),
+            ConnectionError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ConnectionError::WebSocket(ref e) => write!(f, "WebSocket error: {}", e),
+            ConnectionError::Diverged {
+                expected: Some(ref expected),
+                ref actual,
+            } => write!(
+                f,
+                "Request diverged from the recording, expected {:?}, got {:?}",
+                expected, actual
+            ),
+            ConnectionError::Diverged {
+                expected: None,
+                ref actual,
+            } => write!(f, "Request past the end of the recording: {:?}", actual),
+        }
+    }
+}
+
+impl Error for ConnectionError {
+    fn description(&self) -> &str {
+        match *self {
+            ConnectionError::Rejected { .. } => "connection rejected",
+            ConnectionError::InvalidFrameLength => "invalid frame length",
+            ConnectionError::MessageTooLong { .. } => "message too long",
+            ConnectionError::Decode(_) => "invalid message",
+            ConnectionError::Closed => "connection closed",
+            ConnectionError::TimedOut => "timed out",
+            ConnectionError::Io(_) => "I/O error",
+            ConnectionError::WebSocket(_) => "WebSocket error",
+            ConnectionError::Diverged { .. } => "request diverged from recording",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ConnectionError::Decode(ref e) => Some(e),
+            ConnectionError::Io(ref e) => Some(e),
+            ConnectionError::WebSocket(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+/// The timer of a timeout failed, which happens when it is used outside of a
+/// runtime.
+impl From<timer::Error> for ConnectionError {
+    fn from(e: timer::Error) -> Self {
+        ConnectionError::Io(io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+impl From<DecodeError> for ConnectionError {
+    fn from(e: DecodeError) -> Self {
+        ConnectionError::Decode(e)
+    }
+}
+
+impl From<tungstenite::Error> for ConnectionError {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::Io(e) => ConnectionError::Io(e),
+            tungstenite::Error::ConnectionClosed(_) | tungstenite::Error::AlreadyClosed => {
+                ConnectionError::Closed
+            }
+            e => ConnectionError::WebSocket(e),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use tokio::timer::Delay;
+use tokio_io::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::codec;
+use super::{check_handshake, ConnectionBuilder, ConnectionError, RpcConnection};
+use encoding;
+use schema;
+
+/// A connection that reads the responses in the order of the requests, and
+/// can throw away the response to a request nobody waits for anymore.
+pub(crate) trait SkipResponses:
+    Sink<SinkItem = schema::Request, SinkError = ConnectionError>
+    + Stream<Item = schema::Response, Error = ConnectionError>
+{
+    /// Skips the next response that isn't skipped already.
+    fn skip_response(&mut self);
+}
+
+/// `RpcConnection::call_timeout` for connections that can skip responses.
+#[derive(Debug)]
+pub(crate) struct TimedCall<C> {
+    connection: Option<C>,
+    /// The request, until the connection accepted it.
+    request: Option<schema::Request>,
+    deadline: Delay,
+}
+
+impl<C: SkipResponses> TimedCall<C> {
+    pub(crate) fn new(connection: C, request: schema::Request, timeout: Duration) -> Self {
+        TimedCall {
+            connection: Some(connection),
+            request: Some(request),
+            deadline: Delay::new(Instant::now() + timeout),
+        }
+    }
+
+    fn poll_response(&mut self) -> Result<Async<schema::Response>, ConnectionError> {
+        let connection = self.connection
+            .as_mut()
+            .expect("TimedCall polled after completion");
+
+        if let Some(request) = self.request.take() {
+            if let AsyncSink::NotReady(request) = connection.start_send(request)? {
+                self.request = Some(request);
+                return Ok(Async::NotReady);
+            }
+        }
+        try_ready!(connection.poll_complete());
+
+        match try_ready!(connection.poll()) {
+            Some(response) => Ok(Async::Ready(response)),
+            None => Err(ConnectionError::Closed),
+        }
+    }
+}
+
+impl<C: SkipResponses> Future for TimedCall<C> {
+    type Item = (Option<schema::Response>, C);
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, ConnectionError> {
+        let response = match self.poll_response()? {
+            Async::Ready(response) => Some(response),
+            Async::NotReady => {
+                try_ready!(self.deadline.poll());
+                // A request that wasn't accepted yet is never sent
+                if self.request.is_none() {
+                    if let Some(ref mut connection) = self.connection {
+                        connection.skip_response();
+                    }
+                }
+                None
+            }
+        };
+
+        let connection = self.connection
+            .take()
+            .expect("TimedCall polled after completion");
+        Ok(Async::Ready((response, connection)))
+    }
+}
+
+#[derive(Debug)]
+pub struct TokioConnection<A> {
+    inner: Framed<A, codec::VarintFramedCodec>,
+    handshake_response: schema::ConnectionResponse,
+    /// Responses to timed out calls that are still to come.
+    skipped: usize,
+}
+
+impl<A> TokioConnection<A>
+where
+    A: AsyncRead + AsyncWrite + 'static,
+{
+    /// Connects with the default settings of `ConnectionBuilder`.
+    pub fn initialize(io: A) -> impl Future<Item = Self, Error = ConnectionError> {
+        ConnectionBuilder::new().initialize(io)
+    }
+}
+
+impl<A> TokioConnection<A> {
+    /// The identifier the server assigned to this client. It is needed to
+    /// open the matching `StreamConnection`.
+    pub fn client_identifier(&self) -> &[u8] {
+        &self.handshake_response.client_identifier
+    }
+
+    /// The server's answer to the connection request.
+    pub fn handshake_response(&self) -> &schema::ConnectionResponse {
+        &self.handshake_response
+    }
+}
+
+impl ConnectionBuilder {
+    #[async]
+    pub fn initialize<A>(self, io: A) -> Result<TokioConnection<A>, ConnectionError>
+    where
+        A: AsyncRead + AsyncWrite + 'static,
+    {
+        let framed = io.framed(self.codec());
+
+        let request = schema::ConnectionRequest {
+            type_: schema::connection_request::Type::Rpc.into(),
+            client_name: self.client_name,
+            client_identifier: Vec::new(),
+        };
+
+        let request = encoding::encode_message(&request);
+        let (inner, handshake_response) = await!(do_handshake(framed, request))?;
+
+        Ok(TokioConnection {
+            inner,
+            handshake_response,
+            skipped: 0,
+        })
+    }
+}
+
+/// Sends the encoded connection request and waits for the server to accept
+/// it. The request is a `ConnectionRequest`, possibly wrapped for transports
+/// like SerialIO.
+#[async]
+pub(crate) fn do_handshake<A>(
+    t: Framed<A, codec::VarintFramedCodec>,
+    request: Vec<u8>,
+) -> Result<(Framed<A, codec::VarintFramedCodec>, schema::ConnectionResponse), ConnectionError>
+where
+    A: AsyncRead + AsyncWrite + 'static,
+{
+    use futures::Sink;
+    use prost::Message;
+
+    use bytes::buf::IntoBuf;
+    let t = await!(t.send(request))?;
+    let (response, t) = await!(t.into_future().map_err(|(e, _)| e))?;
+    let response = response.ok_or(ConnectionError::Closed)?;
+    let response = schema::ConnectionResponse::decode(&mut response.into_buf())?;
+
+    Ok((t, check_handshake(response)?))
+}
+
+impl<A> Sink for TokioConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    type SinkItem = schema::Request;
+    type SinkError = ConnectionError;
+
+    fn start_send(
+        &mut self,
+        item: schema::Request,
+    ) -> Result<AsyncSink<schema::Request>, ConnectionError> {
+        let buf = encoding::encode_message(&item);
+
+        let res = match self.inner.start_send(buf)? {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady(_) => AsyncSink::NotReady(item),
+        };
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Result<Async<()>, ConnectionError> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Result<Async<()>, ConnectionError> {
+        self.inner.close()
+    }
+}
+
+impl<A> Stream for TokioConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    type Item = schema::Response;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::Response>>, ConnectionError> {
+        use prost::Message;
+
+        loop {
+            let inner_item = match try_ready!(self.inner.poll()) {
+                Some(v) => v,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let response = schema::Response::decode(inner_item)?;
+            if self.skipped > 0 {
+                self.skipped -= 1;
+                continue;
+            }
+            return Ok(Async::Ready(Some(response)));
+        }
+    }
+}
+
+impl<A> SkipResponses for TokioConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    fn skip_response(&mut self) {
+        self.skipped += 1;
+    }
+}
+
+impl<A> RpcConnection for TokioConnection<A>
+where
+    A: AsyncRead + AsyncWrite + Send + 'static,
+{
+    fn call(
+        self,
+        r: schema::Request,
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+        Box::new(async_block! {
+            let s = await!(self.send(r))?;
+            let (response, s) = await!(s.into_future()).map_err(|(e, _)| e)?;
+            let response = response.ok_or(ConnectionError::Closed)?;
+            Ok((response, s))
+        })
+    }
+
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        timeout: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send> {
+        Box::new(TimedCall::new(self, r, timeout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use connection::BlockingConnection;
+    use tests::MockIo;
+
+    use prost::Message;
+
+    #[test]
+    fn test_builder_handshake() {
+        let mut input = Vec::new();
+        schema::ConnectionResponse {
+            status: schema::connection_response::Status::Ok.into(),
+            message: String::new(),
+            client_identifier: vec![9, 8, 7],
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+
+        let io = MockIo::new(input);
+        let written = io.written();
+
+        let connection = ConnectionBuilder::new()
+            .client_name("Orbiter 1")
+            .initialize(io)
+            .wait()
+            .unwrap();
+
+        let request =
+            schema::ConnectionRequest::decode_length_delimited(&written.lock().unwrap()[..])
+                .unwrap();
+        assert_eq!(schema::connection_request::Type::Rpc as i32, request.type_);
+        assert_eq!("Orbiter 1", request.client_name);
+
+        assert_eq!(&[9, 8, 7], connection.client_identifier());
+        assert_eq!(
+            schema::connection_response::Status::Ok as i32,
+            connection.handshake_response().status
+        );
+    }
+
+    #[test]
+    fn test_late_response_skipped() {
+        use std::net::TcpListener;
+        use std::thread;
+        use tokio::net::TcpStream;
+        use tokio::runtime::current_thread::Runtime;
+
+        fn response(value: u8) -> schema::Response {
+            schema::Response {
+                error: None,
+                results: vec![schema::ProcedureResult {
+                    error: None,
+                    value: vec![value],
+                }],
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut connection = BlockingConnection::new(socket);
+            let _: schema::ConnectionRequest = connection.receive().unwrap();
+            connection
+                .send(&schema::ConnectionResponse {
+                    status: schema::connection_response::Status::Ok.into(),
+                    ..Default::default()
+                })
+                .unwrap();
+
+            // Answers the first request after its deadline
+            let _: schema::Request = connection.receive().unwrap();
+            thread::sleep(Duration::from_millis(200));
+            connection.send(&response(1)).unwrap();
+
+            let _: schema::Request = connection.receive().unwrap();
+            connection.send(&response(2)).unwrap();
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let connect = TcpStream::connect(&addr)
+            .from_err::<ConnectionError>()
+            .and_then(TokioConnection::initialize);
+        let connection = runtime.block_on(connect).unwrap();
+
+        let call = connection.call_timeout(Default::default(), Duration::from_millis(20));
+        let (response, connection) = runtime.block_on(call).unwrap();
+        assert_eq!(None, response);
+
+        let (response, _) = runtime.block_on(connection.call(Default::default())).unwrap();
+        assert_eq!(vec![2], response.results[0].value);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_rejected() {
+        let mut input = Vec::new();
+        schema::ConnectionResponse {
+            status: schema::connection_response::Status::WrongType.into(),
+            message: "Expected an RPC connection".to_string(),
+            client_identifier: Vec::new(),
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+
+        match TokioConnection::initialize(MockIo::new(input)).wait() {
+            Err(ConnectionError::Rejected { status, message }) => {
+                assert_eq!(schema::connection_response::Status::WrongType, status);
+                assert_eq!("Expected an RPC connection", message);
+            }
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_handshake_closed() {
+        match TokioConnection::initialize(MockIo::new(Vec::new())).wait() {
+            Err(ConnectionError::Closed) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
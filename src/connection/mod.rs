@@ -1,11 +1,38 @@
+mod blocking;
 mod codec;
+mod error;
+#[cfg(feature = "async")]
+mod framed;
+mod record;
+#[cfg(feature = "async")]
+mod serial;
+#[cfg(feature = "async")]
+mod stream;
 mod varint;
-
-use std::io;
-
+#[cfg(feature = "async")]
+mod websocket;
+
+pub(crate) use self::blocking::BlockingConnection;
+pub use self::error::ConnectionError;
+#[cfg(feature = "async")]
+pub use self::framed::TokioConnection;
+#[cfg(feature = "async")]
+use self::framed::{do_handshake, SkipResponses, TimedCall};
+pub use self::record::{Recorder, RecordingConnection, RecordingUpdates, ReplayConnection,
+                       ReplayUpdates};
+#[cfg(feature = "async")]
+pub use self::serial::{SerialConnection, SerialStreamUpdates};
+#[cfg(feature = "async")]
+pub use self::stream::StreamConnection;
+#[cfg(feature = "async")]
+pub use self::websocket::{WebSocketConnection, WebSocketStreamConnection};
+
+use std::time::{Duration, Instant};
+
+use futures::future::Either;
 use futures::prelude::*;
-use tokio_io::codec::Framed;
-use tokio_io::{AsyncRead, AsyncWrite};
+use prost::DecodeError;
+use tokio::timer::Delay;
 
 use schema;
 
@@ -13,123 +40,90 @@ pub trait RpcConnection: 'static {
     fn call(
         self,
         r: schema::Request,
-    ) -> Box<Future<Item = (schema::Response, Self), Error = io::Error> + Send>;
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send>;
+
+    /// Like `call`, but gives up waiting after `timeout` and resolves to no
+    /// response together with the connection. The late response is skipped
+    /// once it arrives, so the connection stays usable.
+    ///
+    /// Connections that can't skip a response, which is the default, fail
+    /// with `ConnectionError::TimedOut` instead. They are dropped together
+    /// with the unanswered request, so no late response can be taken for the
+    /// answer to a later one.
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        timeout: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send>
+    where
+        Self: Sized,
+    {
+        let deadline = Delay::new(Instant::now() + timeout);
+        Box::new(
+            self.call(r)
+                .select2(deadline)
+                .then(|result| match result {
+                    Ok(Either::A(((response, connection), _))) => Ok((Some(response), connection)),
+                    Ok(Either::B(_)) => Err(ConnectionError::TimedOut),
+                    Err(Either::A((e, _))) => Err(e),
+                    Err(Either::B((e, _))) => Err(e.into()),
+                }),
+        )
+    }
 }
 
-#[derive(Debug)]
-pub struct TokioConnection<A> {
-    inner: Framed<A, codec::VarintFramedCodec>,
-    handshake_response: schema::ConnectionResponse,
+/// Settings for the handshake of a new RPC connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionBuilder {
+    client_name: String,
+    max_message_size: usize,
 }
 
-impl<A> TokioConnection<A>
-where
-    A: AsyncRead + AsyncWrite + 'static,
-{
-    #[async]
-    pub fn initialize(io: A) -> io::Result<Self> {
-        let framed = io.framed(codec::VarintFramedCodec);
-
-        await!(do_handshake(framed))
+impl Default for ConnectionBuilder {
+    fn default() -> Self {
+        ConnectionBuilder {
+            client_name: "kai".to_owned(),
+            max_message_size: codec::DEFAULT_MAX_MESSAGE_SIZE,
+        }
     }
 }
 
-#[async]
-fn do_handshake<A>(t: Framed<A, codec::VarintFramedCodec>) -> io::Result<TokioConnection<A>>
-where
-    A: AsyncRead + AsyncWrite + 'static,
-{
-    use futures::Sink;
-    use prost::Message;
-
-    let request = schema::ConnectionRequest {
-        type_: schema::connection_request::Type::Rpc.into(),
-        client_name: "Test".to_owned(),
-        client_identifier: Vec::new(),
-    };
-
-    let mut buf = Vec::new();
-
-    request.encode(&mut buf)?;
-
-    use bytes::buf::IntoBuf;
-    let t = await!(t.send(buf))?;
-    let (response, t) = await!(t.into_future().map_err(|(e, _)| e))?;
-    let response =
-        response.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No connection response"))?;
-    let response = schema::ConnectionResponse::decode(&mut response.into_buf())
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    Ok(TokioConnection {
-        inner: t,
-        handshake_response: response,
-    })
-}
-
-impl<A> Sink for TokioConnection<A>
-where
-    A: AsyncRead + AsyncWrite,
-{
-    type SinkItem = schema::Request;
-    type SinkError = io::Error;
-
-    fn start_send(
-        &mut self,
-        item: schema::Request,
-    ) -> Result<AsyncSink<schema::Request>, io::Error> {
-        use prost::Message;
-
-        let mut buf = Vec::new();
-        item.encode(&mut buf)?;
-
-        let res = match self.inner.start_send(buf)? {
-            AsyncSink::Ready => AsyncSink::Ready,
-            AsyncSink::NotReady(_) => AsyncSink::NotReady(item),
-        };
-        Ok(res)
+impl ConnectionBuilder {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn poll_complete(&mut self) -> Result<Async<()>, io::Error> {
-        self.inner.poll_complete()
+    /// The name shown for this client in the in-game client list.
+    pub fn client_name<S: Into<String>>(mut self, client_name: S) -> Self {
+        self.client_name = client_name.into();
+        self
     }
 
-    fn close(&mut self) -> Result<Async<()>, io::Error> {
-        self.inner.close()
+    /// The longest message, in bytes, that may be sent or received. Longer
+    /// ones fail with `ConnectionError::MessageTooLong`. Defaults to 64 MiB.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
     }
-}
-
-impl<A> Stream for TokioConnection<A>
-where
-    A: AsyncRead + AsyncWrite,
-{
-    type Item = schema::Response;
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Result<Async<Option<schema::Response>>, io::Error> {
-        use prost::Message;
 
-        let inner_item = match try_ready!(self.inner.poll()) {
-            Some(v) => v,
-            None => return Ok(Async::Ready(None)),
-        };
-
-        let response = schema::Response::decode(inner_item)?;
-        Ok(Async::Ready(Some(response)))
+    fn codec(&self) -> codec::VarintFramedCodec {
+        codec::VarintFramedCodec::new(self.max_message_size)
     }
 }
 
-impl<A> RpcConnection for TokioConnection<A>
-where
-    A: AsyncRead + AsyncWrite + Send + 'static,
-{
-    fn call(
-        self,
-        r: schema::Request,
-    ) -> Box<Future<Item = (schema::Response, Self), Error = io::Error> + Send> {
-        Box::new(async_block! {
-            let s = await!(self.send(r))?;
-            let (response, s) = await!(s.into_future()).map_err(|(e, _)| e)?;
-            let response = response.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No Response"))?;
-            Ok((response, s))
-        })
+/// Fails unless the server accepted the connection request.
+fn check_handshake(
+    response: schema::ConnectionResponse,
+) -> Result<schema::ConnectionResponse, ConnectionError> {
+    use schema::connection_response::Status;
+
+    match Status::from_i32(response.status) {
+        Some(Status::Ok) => Ok(response),
+        Some(status) => Err(ConnectionError::Rejected {
+            status,
+            message: response.message,
+        }),
+        None => Err(DecodeError::new("invalid connection status").into()),
     }
 }
+
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Buf;
+use futures::future;
+use futures::prelude::*;
+use futures::task::{self, Task};
+use prost::Message;
+
+use super::{ConnectionError, RpcConnection};
+use schema;
+
+/// One entry of a recording: either a call with its response or a stream
+/// update.
+#[derive(Clone, PartialEq, Message)]
+struct Record {
+    /// Microseconds since the recording started, taken when the response or
+    /// update arrived.
+    #[prost(uint64, tag = "1")]
+    timestamp: u64,
+    #[prost(message, optional, tag = "2")]
+    request: Option<schema::Request>,
+    #[prost(message, optional, tag = "3")]
+    response: Option<schema::Response>,
+    #[prost(message, optional, tag = "4")]
+    stream_update: Option<schema::StreamUpdate>,
+}
+
+/// Writes the traffic of a session to a file, to be played back later by a
+/// `ReplayConnection`.
+///
+/// Each call and each stream update is written as a length-delimited record
+/// as soon as it is complete. Clones write to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Mutex<RecorderInner>>,
+}
+
+struct RecorderInner {
+    writer: Box<Write + Send>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Recorder {
+            inner: Arc::new(Mutex::new(RecorderInner {
+                writer: Box::new(writer),
+                start: Instant::now(),
+            })),
+        }
+    }
+
+    /// Records the calls made on `connection`.
+    pub fn connection<C: RpcConnection>(&self, connection: C) -> RecordingConnection<C> {
+        RecordingConnection {
+            inner: connection,
+            recorder: self.clone(),
+        }
+    }
+
+    /// Records the updates received on `updates`, usually a
+    /// `StreamConnection`.
+    pub fn updates<S>(&self, updates: S) -> RecordingUpdates<S>
+    where
+        S: Stream<Item = schema::StreamUpdate, Error = ConnectionError>,
+    {
+        RecordingUpdates {
+            inner: updates,
+            recorder: self.clone(),
+        }
+    }
+
+    fn write(&self, mut record: Record) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let elapsed = inner.start.elapsed();
+        record.timestamp = elapsed.as_secs() * 1_000_000 + elapsed.subsec_nanos() as u64 / 1000;
+
+        let mut buf = Vec::with_capacity(record.encoded_len() + 10);
+        record
+            .encode_length_delimited(&mut buf)
+            .expect("Vec<u8> has enough capacity for any message");
+        inner.writer.write_all(&buf)?;
+        inner.writer.flush()
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+/// An `RpcConnection` that records every call made through it.
+#[derive(Debug)]
+pub struct RecordingConnection<C> {
+    inner: C,
+    recorder: Recorder,
+}
+
+impl<C> RecordingConnection<C> {
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: RpcConnection> RpcConnection for RecordingConnection<C> {
+    fn call(
+        self,
+        r: schema::Request,
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+        let RecordingConnection { inner, recorder } = self;
+
+        Box::new(
+            inner
+                .call(r.clone())
+                .and_then(move |(response, inner)| {
+                    recorder.write(Record {
+                        timestamp: 0,
+                        request: Some(r),
+                        response: Some(response.clone()),
+                        stream_update: None,
+                    })?;
+                    Ok((response, RecordingConnection { inner, recorder }))
+                }),
+        )
+    }
+
+    /// A call that timed out is recorded without a response.
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        timeout: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send> {
+        let RecordingConnection { inner, recorder } = self;
+
+        Box::new(
+            inner
+                .call_timeout(r.clone(), timeout)
+                .and_then(move |(response, inner)| {
+                    recorder.write(Record {
+                        timestamp: 0,
+                        request: Some(r),
+                        response: response.clone(),
+                        stream_update: None,
+                    })?;
+                    Ok((response, RecordingConnection { inner, recorder }))
+                }),
+        )
+    }
+}
+
+/// The stream updates of a `Recorder`, passed through after being recorded.
+#[derive(Debug)]
+pub struct RecordingUpdates<S> {
+    inner: S,
+    recorder: Recorder,
+}
+
+impl<S> Stream for RecordingUpdates<S>
+where
+    S: Stream<Item = schema::StreamUpdate, Error = ConnectionError>,
+{
+    type Item = schema::StreamUpdate;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::StreamUpdate>>, ConnectionError> {
+        let update = try_ready!(self.inner.poll());
+        if let Some(ref update) = update {
+            self.recorder.write(Record {
+                timestamp: 0,
+                request: None,
+                response: None,
+                stream_update: Some(update.clone()),
+            })?;
+        }
+        Ok(Async::Ready(update))
+    }
+}
+
+/// Plays back a recording of a `Recorder` in place of a server.
+///
+/// Each call has to send the next recorded request, which is answered with
+/// the recorded response, or fails with `ConnectionError::Diverged`. Updates
+/// recorded before a response are handed to the `ReplayUpdates` returned with
+/// the connection once the request is sent. Timing is not replayed, but calls
+/// that timed out while recording time out again under `call_timeout`.
+#[derive(Debug)]
+pub struct ReplayConnection {
+    replay: Arc<Mutex<Replay>>,
+}
+
+#[derive(Debug)]
+struct Replay {
+    records: VecDeque<Record>,
+    updates: VecDeque<schema::StreamUpdate>,
+    /// The `ReplayUpdates` waiting for the next call.
+    task: Option<Task>,
+}
+
+impl ReplayConnection {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, ReplayUpdates), ConnectionError> {
+        Self::read(File::open(path)?)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<(Self, ReplayUpdates), ConnectionError> {
+        let mut recording = Vec::new();
+        reader.read_to_end(&mut recording)?;
+
+        let mut buf = Cursor::new(recording);
+        let mut records = VecDeque::new();
+        while buf.has_remaining() {
+            records.push_back(Record::decode_length_delimited(&mut buf)?);
+        }
+
+        let replay = Arc::new(Mutex::new(Replay {
+            records,
+            updates: VecDeque::new(),
+            task: None,
+        }));
+        Ok((
+            ReplayConnection {
+                replay: replay.clone(),
+            },
+            ReplayUpdates { replay },
+        ))
+    }
+
+    fn replay(
+        &self,
+        request: schema::Request,
+    ) -> Result<Option<schema::Response>, ConnectionError> {
+        let mut replay = self.replay.lock().unwrap();
+
+        while let Some(update) = replay.records.front().and_then(|r| r.stream_update.clone()) {
+            replay.records.pop_front();
+            replay.updates.push_back(update);
+        }
+        if let Some(task) = replay.task.take() {
+            task.notify();
+        }
+
+        let record = match replay.records.pop_front() {
+            Some(record) => record,
+            None => {
+                return Err(ConnectionError::Diverged {
+                    expected: None,
+                    actual: request,
+                })
+            }
+        };
+
+        let expected = record.request.unwrap_or_default();
+        if expected != request {
+            return Err(ConnectionError::Diverged {
+                expected: Some(expected),
+                actual: request,
+            });
+        }
+
+        Ok(record.response)
+    }
+}
+
+impl RpcConnection for ReplayConnection {
+    fn call(
+        self,
+        r: schema::Request,
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+        Box::new(future::result(
+            self.replay(r)
+                .map(|response| (response.unwrap_or_default(), self)),
+        ))
+    }
+
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        _: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send> {
+        Box::new(future::result(
+            self.replay(r).map(|response| (response, self)),
+        ))
+    }
+}
+
+/// The stream updates of a `ReplayConnection`, to be passed to
+/// `Streams::dispatch`. Ends with the recording.
+#[derive(Debug)]
+pub struct ReplayUpdates {
+    replay: Arc<Mutex<Replay>>,
+}
+
+impl Stream for ReplayUpdates {
+    type Item = schema::StreamUpdate;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::StreamUpdate>>, ConnectionError> {
+        let mut replay = self.replay.lock().unwrap();
+
+        if let Some(update) = replay.updates.pop_front() {
+            return Ok(Async::Ready(Some(update)));
+        }
+
+        let update = match replay.records.front() {
+            None => return Ok(Async::Ready(None)),
+            Some(record) => record.stream_update.clone(),
+        };
+        match update {
+            Some(update) => {
+                replay.records.pop_front();
+                Ok(Async::Ready(Some(update)))
+            }
+            None => {
+                // Updates recorded after the next call come once it is made
+                replay.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+mod test {
+    use super::*;
+
+    use encoding;
+    use server::{ProcedureCallError, Server};
+    use services::krpc;
+
+    /// A writer whose output can be read after the recorder is gone.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Answers every call with the status of version `1.<n>`.
+    struct CountingConnection(u32);
+
+    impl RpcConnection for CountingConnection {
+        fn call(
+            self,
+            r: schema::Request,
+        ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+            let status = schema::Status {
+                version: format!("1.{}", self.0),
+                ..Default::default()
+            };
+            let response = schema::Response {
+                error: None,
+                results: r.calls
+                    .iter()
+                    .map(|_| schema::ProcedureResult {
+                        error: None,
+                        value: encoding::encode_message(&status),
+                    })
+                    .collect(),
+            };
+            Box::new(future::ok((response, CountingConnection(self.0 + 1))))
+        }
+    }
+
+    fn update(id: u64) -> schema::StreamUpdate {
+        schema::StreamUpdate {
+            results: vec![schema::StreamResult {
+                id,
+                result: Some(schema::ProcedureResult {
+                    error: None,
+                    value: vec![1],
+                }),
+            }],
+        }
+    }
+
+    fn record() -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let recorder = Recorder::new(buffer.clone());
+
+        let server = Server::new(recorder.connection(CountingConnection(0)));
+        let (_, server) = server.invoke(krpc::GetStatus).wait().unwrap();
+
+        let updates = recorder.updates(::futures::stream::iter_ok(vec![update(1), update(2)]));
+        assert_eq!(2, updates.collect().wait().unwrap().len());
+
+        server.invoke(krpc::GetStatus).wait().unwrap();
+
+        let recording = buffer.0.lock().unwrap();
+        recording.clone()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let (connection, updates) = ReplayConnection::read(&record()[..]).unwrap();
+        let server = Server::new(connection);
+
+        let (status, server) = server.invoke(krpc::GetStatus).wait().unwrap();
+        assert_eq!("1.0", status.version);
+        let (status, server) = server.invoke(krpc::GetStatus).wait().unwrap();
+        assert_eq!("1.1", status.version);
+
+        assert_eq!(vec![update(1), update(2)], updates.collect().wait().unwrap());
+
+        match server.invoke(krpc::GetStatus).wait() {
+            Err(ProcedureCallError::Connection(ConnectionError::Diverged {
+                expected: None, ..
+            })) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_replay_diverged() {
+        let (connection, _) = ReplayConnection::read(&record()[..]).unwrap();
+
+        let request = schema::Request {
+            calls: vec![encoding::procedure_call("KRPC", "GetClientID", Vec::new())],
+        };
+        match connection.call(request.clone()).wait() {
+            Err(ConnectionError::Diverged {
+                expected: Some(expected),
+                actual,
+            }) => {
+                assert_eq!("GetStatus", expected.calls[0].procedure);
+                assert_eq!(request, actual);
+            }
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
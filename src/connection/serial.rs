@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use futures::prelude::*;
+use futures::sync::mpsc;
+use prost::Message;
+use tokio_io::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::codec::VarintFramedCodec;
+use super::{do_handshake, ConnectionBuilder, ConnectionError, RpcConnection, SkipResponses,
+            TimedCall};
+use encoding;
+use schema;
+
+/// An RPC connection to a server using the SerialIO protocol, over a serial
+/// port or anything else that moves bytes.
+///
+/// SerialIO has no second socket for streams. Requests are sent wrapped in a
+/// `MultiplexedRequest` and the server interleaves responses and stream
+/// updates in `MultiplexedResponse`s on the same line. Stream updates are
+/// handed to the `SerialStreamUpdates` returned with the connection, so they
+/// only arrive while the connection is polled.
+#[derive(Debug)]
+pub struct SerialConnection<A> {
+    inner: Framed<A, VarintFramedCodec>,
+    handshake_response: schema::ConnectionResponse,
+    updates: mpsc::UnboundedSender<schema::StreamUpdate>,
+    /// Responses to timed out calls that are still to come.
+    skipped: usize,
+}
+
+impl ConnectionBuilder {
+    /// Connects over the SerialIO protocol. `io` is usually a serial device
+    /// that is already configured with the baud rate the server expects.
+    pub fn initialize_serial<A>(
+        self,
+        io: A,
+    ) -> impl Future<Item = (SerialConnection<A>, SerialStreamUpdates), Error = ConnectionError>
+    where
+        A: AsyncRead + AsyncWrite + 'static,
+    {
+        let framed = io.framed(self.codec());
+
+        let request = schema::MultiplexedRequest {
+            connection_request: Some(schema::ConnectionRequest {
+                type_: schema::connection_request::Type::Rpc.into(),
+                client_name: self.client_name,
+                client_identifier: Vec::new(),
+            }),
+            request: None,
+        };
+
+        do_handshake(framed, encoding::encode_message(&request)).map(
+            |(inner, handshake_response)| {
+                let (updates, receiver) = mpsc::unbounded();
+                let connection = SerialConnection {
+                    inner,
+                    handshake_response,
+                    updates,
+                    skipped: 0,
+                };
+                (connection, SerialStreamUpdates { inner: receiver })
+            },
+        )
+    }
+}
+
+impl<A> SerialConnection<A> {
+    /// The server's answer to the connection request.
+    pub fn handshake_response(&self) -> &schema::ConnectionResponse {
+        &self.handshake_response
+    }
+}
+
+impl<A> Sink for SerialConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    type SinkItem = schema::Request;
+    type SinkError = ConnectionError;
+
+    fn start_send(
+        &mut self,
+        item: schema::Request,
+    ) -> Result<AsyncSink<schema::Request>, ConnectionError> {
+        let request = schema::MultiplexedRequest {
+            connection_request: None,
+            request: Some(item),
+        };
+        let buf = encoding::encode_message(&request);
+
+        let res = match self.inner.start_send(buf)? {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady(_) => AsyncSink::NotReady(request.request.unwrap()),
+        };
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Result<Async<()>, ConnectionError> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Result<Async<()>, ConnectionError> {
+        self.inner.close()
+    }
+}
+
+impl<A> Stream for SerialConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    type Item = schema::Response;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::Response>>, ConnectionError> {
+        loop {
+            let inner_item = match try_ready!(self.inner.poll()) {
+                Some(v) => v,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let message = schema::MultiplexedResponse::decode(inner_item)?;
+            if let Some(update) = message.stream_update {
+                // Nobody listening for updates is not an error.
+                let _ = self.updates.unbounded_send(update);
+            }
+            if let Some(response) = message.response {
+                if self.skipped > 0 {
+                    self.skipped -= 1;
+                    continue;
+                }
+                return Ok(Async::Ready(Some(response)));
+            }
+        }
+    }
+}
+
+impl<A> SkipResponses for SerialConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    fn skip_response(&mut self) {
+        self.skipped += 1;
+    }
+}
+
+impl<A> RpcConnection for SerialConnection<A>
+where
+    A: AsyncRead + AsyncWrite + Send + 'static,
+{
+    fn call(
+        self,
+        r: schema::Request,
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+        Box::new(async_block! {
+            let s = await!(self.send(r))?;
+            let (response, s) = await!(s.into_future()).map_err(|(e, _)| e)?;
+            let response = response.ok_or(ConnectionError::Closed)?;
+            Ok((response, s))
+        })
+    }
+
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        timeout: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send> {
+        Box::new(TimedCall::new(self, r, timeout))
+    }
+}
+
+/// The stream updates received on a `SerialConnection`, to be passed to
+/// `Streams::dispatch`. Ends when the connection is dropped.
+#[derive(Debug)]
+pub struct SerialStreamUpdates {
+    inner: mpsc::UnboundedReceiver<schema::StreamUpdate>,
+}
+
+impl Stream for SerialStreamUpdates {
+    type Item = schema::StreamUpdate;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::StreamUpdate>>, ConnectionError> {
+        // The receiving half of a channel never fails.
+        Ok(self.inner.poll().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tests::MockIo;
+
+    fn handshake_response() -> schema::ConnectionResponse {
+        schema::ConnectionResponse {
+            status: schema::connection_response::Status::Ok.into(),
+            message: String::new(),
+            client_identifier: vec![5, 6],
+        }
+    }
+
+    #[test]
+    fn test_serial_handshake() {
+        let mut input = Vec::new();
+        handshake_response().encode_length_delimited(&mut input).unwrap();
+
+        let io = MockIo::new(input);
+        let written = io.written();
+
+        let (connection, _) = ConnectionBuilder::new()
+            .client_name("Cockpit")
+            .initialize_serial(io)
+            .wait()
+            .unwrap();
+
+        let request =
+            schema::MultiplexedRequest::decode_length_delimited(&written.lock().unwrap()[..])
+                .unwrap();
+        assert_eq!(None, request.request);
+        let request = request.connection_request.unwrap();
+        assert_eq!(schema::connection_request::Type::Rpc as i32, request.type_);
+        assert_eq!("Cockpit", request.client_name);
+
+        assert_eq!(handshake_response(), *connection.handshake_response());
+    }
+
+    #[test]
+    fn test_serial_call_with_stream_update() {
+        let update = schema::StreamUpdate {
+            results: vec![schema::StreamResult {
+                id: 3,
+                result: Some(schema::ProcedureResult {
+                    error: None,
+                    value: vec![1],
+                }),
+            }],
+        };
+        let response = schema::Response {
+            error: None,
+            results: vec![schema::ProcedureResult {
+                error: None,
+                value: vec![2],
+            }],
+        };
+
+        let mut input = Vec::new();
+        handshake_response().encode_length_delimited(&mut input).unwrap();
+        schema::MultiplexedResponse {
+            response: None,
+            stream_update: Some(update.clone()),
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+        schema::MultiplexedResponse {
+            response: Some(response.clone()),
+            stream_update: None,
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+
+        let io = MockIo::new(input);
+        let written = io.written();
+
+        let (connection, updates) = ConnectionBuilder::new()
+            .initialize_serial(io)
+            .wait()
+            .unwrap();
+        written.lock().unwrap().clear();
+
+        let request = schema::Request {
+            calls: vec![encoding::procedure_call("KRPC", "GetStatus", Vec::new())],
+        };
+        let (received, connection) = connection.call(request.clone()).wait().unwrap();
+        assert_eq!(response, received);
+
+        let sent =
+            schema::MultiplexedRequest::decode_length_delimited(&written.lock().unwrap()[..])
+                .unwrap();
+        assert_eq!(None, sent.connection_request);
+        assert_eq!(Some(request), sent.request);
+
+        drop(connection);
+        assert_eq!(vec![update], updates.collect().wait().unwrap());
+    }
+}
@@ -0,0 +1,180 @@
+use futures::prelude::*;
+use tokio_io::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::codec::VarintFramedCodec;
+use super::{do_handshake, ConnectionBuilder, ConnectionError};
+use encoding;
+use schema;
+
+/// The second kRPC socket, over which the server pushes stream updates.
+///
+/// The handshake has to carry the client identifier of an already
+/// established RPC connection, see `TokioConnection::client_identifier`.
+#[derive(Debug)]
+pub struct StreamConnection<A> {
+    inner: Framed<A, VarintFramedCodec>,
+    handshake_response: schema::ConnectionResponse,
+}
+
+impl<A> StreamConnection<A>
+where
+    A: AsyncRead + AsyncWrite + 'static,
+{
+    /// Connects with the default settings of `ConnectionBuilder`.
+    pub fn initialize(
+        io: A,
+        client_identifier: Vec<u8>,
+    ) -> impl Future<Item = Self, Error = ConnectionError> {
+        ConnectionBuilder::new().initialize_stream(io, client_identifier)
+    }
+}
+
+impl ConnectionBuilder {
+    /// Opens the stream connection of the RPC client `client_identifier`. The
+    /// client name isn't sent, the server already knows it.
+    #[async]
+    pub fn initialize_stream<A>(
+        self,
+        io: A,
+        client_identifier: Vec<u8>,
+    ) -> Result<StreamConnection<A>, ConnectionError>
+    where
+        A: AsyncRead + AsyncWrite + 'static,
+    {
+        let framed = io.framed(self.codec());
+
+        let request = schema::ConnectionRequest {
+            type_: schema::connection_request::Type::Stream.into(),
+            client_name: String::new(),
+            client_identifier,
+        };
+
+        let request = encoding::encode_message(&request);
+        let (inner, handshake_response) = await!(do_handshake(framed, request))?;
+
+        Ok(StreamConnection {
+            inner,
+            handshake_response,
+        })
+    }
+}
+
+impl<A> StreamConnection<A> {
+    /// The server's answer to the connection request.
+    pub fn handshake_response(&self) -> &schema::ConnectionResponse {
+        &self.handshake_response
+    }
+}
+
+impl<A> Stream for StreamConnection<A>
+where
+    A: AsyncRead + AsyncWrite,
+{
+    type Item = schema::StreamUpdate;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::StreamUpdate>>, ConnectionError> {
+        use prost::Message;
+
+        let inner_item = match try_ready!(self.inner.poll()) {
+            Some(v) => v,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let update = schema::StreamUpdate::decode(inner_item)?;
+        Ok(Async::Ready(Some(update)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tests::MockIo;
+
+    use prost::Message;
+
+    #[test]
+    fn test_stream_handshake_and_updates() {
+        let client_identifier = vec![1, 2, 3, 4];
+
+        let mut input = Vec::new();
+        schema::ConnectionResponse {
+            status: schema::connection_response::Status::Ok.into(),
+            message: String::new(),
+            client_identifier: client_identifier.clone(),
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+
+        let updates = vec![
+            schema::StreamUpdate {
+                results: vec![schema::StreamResult {
+                    id: 7,
+                    result: Some(schema::ProcedureResult {
+                        error: None,
+                        value: vec![42],
+                    }),
+                }],
+            },
+            schema::StreamUpdate { results: vec![] },
+        ];
+        for update in &updates {
+            update.encode_length_delimited(&mut input).unwrap();
+        }
+
+        let io = MockIo::new(input);
+        let written = io.written();
+
+        let connection = StreamConnection::initialize(io, client_identifier.clone())
+            .wait()
+            .unwrap();
+
+        let request =
+            schema::ConnectionRequest::decode_length_delimited(&written.lock().unwrap()[..])
+                .unwrap();
+        assert_eq!(
+            schema::connection_request::Type::Stream as i32,
+            request.type_
+        );
+        assert_eq!(client_identifier, request.client_identifier);
+        assert_eq!(
+            client_identifier,
+            connection.handshake_response().client_identifier
+        );
+
+        let received = connection.collect().wait().unwrap();
+        assert_eq!(updates, received);
+    }
+
+    #[test]
+    fn test_stream_message_size_limit() {
+        let mut input = Vec::new();
+        schema::ConnectionResponse {
+            status: schema::connection_response::Status::Ok.into(),
+            message: String::new(),
+            client_identifier: vec![1],
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+        schema::StreamUpdate {
+            results: vec![schema::StreamResult {
+                id: 7,
+                result: Some(schema::ProcedureResult {
+                    error: None,
+                    value: vec![0; 64],
+                }),
+            }],
+        }.encode_length_delimited(&mut input)
+            .unwrap();
+
+        let connection = ConnectionBuilder::new()
+            .max_message_size(32)
+            .initialize_stream(MockIo::new(input), vec![1])
+            .wait()
+            .unwrap();
+
+        match connection.collect().wait() {
+            Err(ConnectionError::MessageTooLong { max: 32, .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
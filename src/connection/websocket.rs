@@ -0,0 +1,291 @@
+use std::time::Duration;
+
+use base64;
+use futures::prelude::*;
+use prost::{DecodeError, Message as ProstMessage};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tungstenite::Message;
+use url::Url;
+
+use super::{ConnectionBuilder, ConnectionError, RpcConnection, SkipResponses, TimedCall};
+use encoding;
+use schema;
+
+/// An RPC connection to a server using the WebSocket protocol. Requests and
+/// responses travel as binary messages.
+///
+/// There is no connection response on WebSockets, the client identifier for
+/// the stream connection has to be fetched with `KRPC.GetClientID`.
+#[derive(Debug)]
+pub struct WebSocketConnection<S> {
+    inner: WebSocketStream<S>,
+    /// Responses to timed out calls that are still to come.
+    skipped: usize,
+}
+
+impl ConnectionBuilder {
+    /// Connects to the server at `url` over `io`, which is already connected
+    /// to its host. The client name is sent in the query of the URL.
+    pub fn initialize_websocket<S>(
+        self,
+        url: &Url,
+        io: S,
+    ) -> impl Future<Item = WebSocketConnection<S>, Error = ConnectionError>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        client_async(rpc_url(url, &self.client_name), io)
+            .map(|(inner, _)| WebSocketConnection { inner, skipped: 0 })
+            .map_err(ConnectionError::from)
+    }
+}
+
+fn rpc_url(url: &Url, client_name: &str) -> Url {
+    let mut url = url.clone();
+    url.query_pairs_mut().append_pair("name", client_name);
+    url
+}
+
+fn stream_url(url: &Url, client_identifier: &[u8]) -> Url {
+    let mut url = url.clone();
+    url.query_pairs_mut().append_pair("id", &base64::encode(client_identifier));
+    url
+}
+
+/// Reads the next binary message, skipping control frames.
+fn poll_binary<S>(inner: &mut WebSocketStream<S>) -> Result<Async<Option<Vec<u8>>>, ConnectionError>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    loop {
+        match try_ready!(inner.poll()) {
+            Some(Message::Binary(data)) => return Ok(Async::Ready(Some(data))),
+            Some(Message::Text(_)) => {
+                return Err(DecodeError::new("unexpected text message").into())
+            }
+            Some(_) => continue,
+            None => return Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<S> Sink for WebSocketConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type SinkItem = schema::Request;
+    type SinkError = ConnectionError;
+
+    fn start_send(
+        &mut self,
+        item: schema::Request,
+    ) -> Result<AsyncSink<schema::Request>, ConnectionError> {
+        let message = Message::Binary(encoding::encode_message(&item));
+
+        let res = match self.inner.start_send(message)? {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady(_) => AsyncSink::NotReady(item),
+        };
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Result<Async<()>, ConnectionError> {
+        Ok(self.inner.poll_complete()?)
+    }
+
+    fn close(&mut self) -> Result<Async<()>, ConnectionError> {
+        Ok(self.inner.close()?)
+    }
+}
+
+impl<S> Stream for WebSocketConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = schema::Response;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::Response>>, ConnectionError> {
+        loop {
+            let data = match try_ready!(poll_binary(&mut self.inner)) {
+                Some(data) => data,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let response = schema::Response::decode(data)?;
+            if self.skipped > 0 {
+                self.skipped -= 1;
+                continue;
+            }
+            return Ok(Async::Ready(Some(response)));
+        }
+    }
+}
+
+impl<S> SkipResponses for WebSocketConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn skip_response(&mut self) {
+        self.skipped += 1;
+    }
+}
+
+impl<S> RpcConnection for WebSocketConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    fn call(
+        self,
+        r: schema::Request,
+    ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+        Box::new(async_block! {
+            let s = await!(self.send(r))?;
+            let (response, s) = await!(s.into_future()).map_err(|(e, _)| e)?;
+            let response = response.ok_or(ConnectionError::Closed)?;
+            Ok((response, s))
+        })
+    }
+
+    fn call_timeout(
+        self,
+        r: schema::Request,
+        timeout: Duration,
+    ) -> Box<Future<Item = (Option<schema::Response>, Self), Error = ConnectionError> + Send> {
+        Box::new(TimedCall::new(self, r, timeout))
+    }
+}
+
+/// The WebSocket counterpart of `StreamConnection`.
+#[derive(Debug)]
+pub struct WebSocketStreamConnection<S> {
+    inner: WebSocketStream<S>,
+}
+
+impl<S> WebSocketStreamConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Connects to the stream server at `url`. The identifier of the RPC
+    /// client is sent base64 encoded in the query of the URL.
+    pub fn initialize(
+        url: &Url,
+        client_identifier: &[u8],
+        io: S,
+    ) -> impl Future<Item = Self, Error = ConnectionError> {
+        client_async(stream_url(url, client_identifier), io)
+            .map(|(inner, _)| WebSocketStreamConnection { inner })
+            .map_err(ConnectionError::from)
+    }
+}
+
+impl<S> Stream for WebSocketStreamConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = schema::StreamUpdate;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<Option<schema::StreamUpdate>>, ConnectionError> {
+        let data = match try_ready!(poll_binary(&mut self.inner)) {
+            Some(data) => data,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let update = schema::StreamUpdate::decode(data)?;
+        Ok(Async::Ready(Some(update)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::accept_hdr_async;
+    use tungstenite::handshake::server::Request;
+
+    #[test]
+    fn test_urls() {
+        let url = Url::parse("ws://127.0.0.1:50000/").unwrap();
+
+        assert_eq!(
+            "ws://127.0.0.1:50000/?name=Rover+1",
+            rpc_url(&url, "Rover 1").as_str()
+        );
+        assert_eq!(
+            "ws://127.0.0.1:50000/?id=AQID%2F%2Fw%3D",
+            stream_url(&url, &[1, 2, 3, 255, 252]).as_str()
+        );
+    }
+
+    /// Accepts one connection and answers the first request with the name of
+    /// its first procedure.
+    fn mock_server(listener: TcpListener, path: Arc<Mutex<String>>) {
+        let (socket, _) = listener
+            .incoming()
+            .into_future()
+            .wait()
+            .map_err(|(e, _)| e)
+            .unwrap();
+
+        let ws = accept_hdr_async(socket.unwrap(), move |request: &Request| {
+            *path.lock().unwrap() = request.path.clone();
+            Ok(None)
+        }).wait()
+            .unwrap();
+
+        let (message, ws) = ws.into_future().wait().map_err(|(e, _)| e).unwrap();
+        let request = match message {
+            Some(Message::Binary(data)) => schema::Request::decode(data).unwrap(),
+            other => panic!("Unexpected message: {:?}", other),
+        };
+
+        let response = schema::Response {
+            error: None,
+            results: vec![schema::ProcedureResult {
+                error: None,
+                value: encoding::encode_string(&request.calls[0].procedure),
+            }],
+        };
+        ws.send(Message::Binary(encoding::encode_message(&response)))
+            .wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_websocket_call() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let path = Arc::new(Mutex::new(String::new()));
+        let server = {
+            let path = path.clone();
+            thread::spawn(move || mock_server(listener, path))
+        };
+
+        let url = Url::parse(&format!("ws://{}/", addr)).unwrap();
+        let io = TcpStream::connect(&addr).wait().unwrap();
+        let connection = ConnectionBuilder::new()
+            .client_name("Rover 1")
+            .initialize_websocket(&url, io)
+            .wait()
+            .unwrap();
+
+        let request = schema::Request {
+            calls: vec![encoding::procedure_call("KRPC", "GetStatus", Vec::new())],
+        };
+        let (response, _) = connection.call(request).wait().unwrap();
+
+        server.join().unwrap();
+        assert_eq!("/?name=Rover+1", *path.lock().unwrap());
+        assert_eq!(
+            "GetStatus",
+            encoding::decode_string(&response.results[0].value).unwrap()
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! The services of a server as described by `KRPC.GetServices`, for tools
+//! and callers that only learn about procedures at runtime.
+
+use std::collections::HashMap;
+
+use schema::{self, type_::TypeCode};
+
+/// The description of all services on a server, with lookups by name.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServiceTree {
+    services: schema::Services,
+}
+
+/// What a class or enumeration `Type` refers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Definition<'a> {
+    Class(&'a schema::Class),
+    Enumeration(&'a schema::Enumeration),
+}
+
+/// How a procedure belongs to a class, going by kRPC's naming convention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberKind {
+    /// `Class_Name`, taking the object as first argument.
+    Method,
+    /// `Class_static_Name`
+    StaticMethod,
+    /// `Class_get_Name`
+    Getter,
+    /// `Class_set_Name`
+    Setter,
+}
+
+/// A procedure that is a method or property accessor of a class.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Member<'a> {
+    pub kind: MemberKind,
+    /// The name without the class and kind, e.g. `Name` for `Vessel_get_Name`.
+    pub name: &'a str,
+    pub procedure: &'a schema::Procedure,
+}
+
+/// A property of a class, made of its getter and setter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub getter: Option<&'a schema::Procedure>,
+    pub setter: Option<&'a schema::Procedure>,
+}
+
+impl ServiceTree {
+    pub fn new(services: schema::Services) -> Self {
+        ServiceTree { services }
+    }
+
+    pub fn services(&self) -> &[schema::Service] {
+        &self.services.services
+    }
+
+    pub fn into_inner(self) -> schema::Services {
+        self.services
+    }
+
+    pub fn service(&self, name: &str) -> Option<&schema::Service> {
+        self.services().iter().find(|s| s.name == name)
+    }
+
+    pub fn procedure(&self, service: &str, name: &str) -> Option<&schema::Procedure> {
+        self.service(service)?
+            .procedures
+            .iter()
+            .find(|p| p.name == name)
+    }
+
+    pub fn class(&self, service: &str, name: &str) -> Option<&schema::Class> {
+        self.service(service)?.classes.iter().find(|c| c.name == name)
+    }
+
+    pub fn enumeration(&self, service: &str, name: &str) -> Option<&schema::Enumeration> {
+        self.service(service)?
+            .enumerations
+            .iter()
+            .find(|e| e.name == name)
+    }
+
+    /// The class or enumeration `t` refers to. `None` for other types and
+    /// unknown names.
+    pub fn resolve(&self, t: &schema::Type) -> Option<Definition> {
+        match TypeCode::from_i32(t.code)? {
+            TypeCode::Class => self.class(&t.service, &t.name).map(Definition::Class),
+            TypeCode::Enumeration => self.enumeration(&t.service, &t.name)
+                .map(Definition::Enumeration),
+            _ => None,
+        }
+    }
+
+    /// All procedures of `class`, in the order of the service.
+    pub fn members<'a>(&'a self, service: &str, class: &str) -> Vec<Member<'a>> {
+        let service = match self.service(service) {
+            Some(service) => service,
+            None => return Vec::new(),
+        };
+
+        service
+            .procedures
+            .iter()
+            .filter_map(|procedure| member(class, procedure))
+            .collect()
+    }
+
+    /// The methods of `class`, static ones included.
+    pub fn methods<'a>(&'a self, service: &str, class: &str) -> Vec<Member<'a>> {
+        self.members(service, class)
+            .into_iter()
+            .filter(|m| m.kind == MemberKind::Method || m.kind == MemberKind::StaticMethod)
+            .collect()
+    }
+
+    /// The properties of `class`, in the order of their first accessor.
+    pub fn properties<'a>(&'a self, service: &str, class: &str) -> Vec<Property<'a>> {
+        let mut properties: Vec<Property> = Vec::new();
+
+        for member in self.members(service, class) {
+            if member.kind != MemberKind::Getter && member.kind != MemberKind::Setter {
+                continue;
+            }
+
+            let index = match properties.iter().position(|p| p.name == member.name) {
+                Some(index) => index,
+                None => {
+                    properties.push(Property {
+                        name: member.name,
+                        getter: None,
+                        setter: None,
+                    });
+                    properties.len() - 1
+                }
+            };
+
+            if member.kind == MemberKind::Getter {
+                properties[index].getter = Some(member.procedure);
+            } else {
+                properties[index].setter = Some(member.procedure);
+            }
+        }
+
+        properties
+    }
+}
+
+impl From<schema::Services> for ServiceTree {
+    fn from(services: schema::Services) -> Self {
+        Self::new(services)
+    }
+}
+
+/// The numeric ids of the procedures in a `ServiceTree`, for calls that are
+/// sent without names. kRPC numbers services and the procedures of each
+/// service by their position in the services list, starting at 1.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProcedureIds {
+    services: HashMap<String, (u32, HashMap<String, u32>)>,
+}
+
+impl ProcedureIds {
+    pub fn new(services: &ServiceTree) -> Self {
+        let services = services
+            .services()
+            .iter()
+            .enumerate()
+            .map(|(i, service)| {
+                let procedures = service
+                    .procedures
+                    .iter()
+                    .enumerate()
+                    .map(|(j, procedure)| (procedure.name.clone(), j as u32 + 1))
+                    .collect();
+                (service.name.clone(), (i as u32 + 1, procedures))
+            })
+            .collect();
+
+        ProcedureIds { services }
+    }
+
+    /// The service id and procedure id of `service.procedure`.
+    pub fn get(&self, service: &str, procedure: &str) -> Option<(u32, u32)> {
+        let (service_id, ref procedures) = *self.services.get(service)?;
+        procedures.get(procedure).map(|&id| (service_id, id))
+    }
+
+    /// Replaces the names in `call` by ids. Calls of unknown procedures keep
+    /// their names.
+    pub fn resolve(&self, call: &mut schema::ProcedureCall) {
+        if let Some((service_id, procedure_id)) = self.get(&call.service, &call.procedure) {
+            call.service_id = service_id;
+            call.procedure_id = procedure_id;
+            call.service.clear();
+            call.procedure.clear();
+        }
+    }
+
+    pub fn resolve_request(&self, request: &mut schema::Request) {
+        for call in &mut request.calls {
+            self.resolve(call);
+        }
+    }
+}
+
+/// `procedure` as a member of `class`, if its name starts with `class_`.
+fn member<'a>(class: &str, procedure: &'a schema::Procedure) -> Option<Member<'a>> {
+    let name = &procedure.name;
+    if !name.starts_with(class) || !name[class.len()..].starts_with('_') {
+        return None;
+    }
+    let name = &name[class.len() + 1..];
+
+    let (kind, name) = if name.starts_with("get_") {
+        (MemberKind::Getter, &name[4..])
+    } else if name.starts_with("set_") {
+        (MemberKind::Setter, &name[4..])
+    } else if name.starts_with("static_") {
+        (MemberKind::StaticMethod, &name[7..])
+    } else {
+        (MemberKind::Method, name)
+    };
+
+    Some(Member {
+        kind,
+        name,
+        procedure,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree() -> ServiceTree {
+        let procedure = |name: &str| schema::Procedure {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        ServiceTree::new(schema::Services {
+            services: vec![schema::Service {
+                name: "SpaceCenter".to_string(),
+                procedures: vec![
+                    procedure("get_ActiveVessel"),
+                    procedure("Vessel_get_Name"),
+                    procedure("Vessel_set_Name"),
+                    procedure("Vessel_Flight"),
+                    procedure("Vessel_get_MET"),
+                    procedure("VesselType_Unrelated"),
+                    procedure("Vessel_static_Launch"),
+                ],
+                classes: vec![schema::Class {
+                    name: "Vessel".to_string(),
+                    ..Default::default()
+                }],
+                enumerations: vec![schema::Enumeration {
+                    name: "VesselType".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    #[test]
+    fn test_lookup() {
+        let tree = tree();
+
+        assert_eq!(
+            "Vessel_Flight",
+            tree.procedure("SpaceCenter", "Vessel_Flight").unwrap().name
+        );
+        assert_eq!(None, tree.procedure("SpaceCenter", "Flight"));
+        assert_eq!(None, tree.procedure("KRPC", "Vessel_Flight"));
+
+        let t = |code: TypeCode, name: &str| schema::Type {
+            code: code as i32,
+            service: "SpaceCenter".to_string(),
+            name: name.to_string(),
+            types: Vec::new(),
+        };
+        match tree.resolve(&t(TypeCode::Class, "Vessel")) {
+            Some(Definition::Class(class)) => assert_eq!("Vessel", class.name),
+            other => panic!("Unexpected definition: {:?}", other),
+        }
+        match tree.resolve(&t(TypeCode::Enumeration, "VesselType")) {
+            Some(Definition::Enumeration(e)) => assert_eq!("VesselType", e.name),
+            other => panic!("Unexpected definition: {:?}", other),
+        }
+        assert_eq!(None, tree.resolve(&t(TypeCode::Class, "VesselType")));
+        assert_eq!(None, tree.resolve(&t(TypeCode::Double, "")));
+    }
+
+    #[test]
+    fn test_class_members() {
+        let tree = tree();
+
+        let methods = tree.methods("SpaceCenter", "Vessel")
+            .iter()
+            .map(|m| (m.kind, m.name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (MemberKind::Method, "Flight"),
+                (MemberKind::StaticMethod, "Launch"),
+            ],
+            methods
+        );
+
+        let properties = tree.properties("SpaceCenter", "Vessel");
+        assert_eq!(2, properties.len());
+        assert_eq!("Name", properties[0].name);
+        assert_eq!("Vessel_get_Name", properties[0].getter.unwrap().name);
+        assert_eq!("Vessel_set_Name", properties[0].setter.unwrap().name);
+        assert_eq!("MET", properties[1].name);
+        assert_eq!(None, properties[1].setter);
+    }
+
+    #[test]
+    fn test_procedure_ids() {
+        let ids = ProcedureIds::new(&tree());
+
+        assert_eq!(Some((1, 1)), ids.get("SpaceCenter", "get_ActiveVessel"));
+        assert_eq!(Some((1, 4)), ids.get("SpaceCenter", "Vessel_Flight"));
+        assert_eq!(None, ids.get("SpaceCenter", "Flight"));
+
+        let call = |service: &str, procedure: &str| schema::ProcedureCall {
+            service: service.to_string(),
+            procedure: procedure.to_string(),
+            ..Default::default()
+        };
+
+        let mut resolved = call("SpaceCenter", "Vessel_Flight");
+        ids.resolve(&mut resolved);
+        assert_eq!(
+            schema::ProcedureCall {
+                service_id: 1,
+                procedure_id: 4,
+                ..Default::default()
+            },
+            resolved
+        );
+
+        let mut unknown = call("KRPC", "GetStatus");
+        ids.resolve(&mut unknown);
+        assert_eq!(call("KRPC", "GetStatus"), unknown);
+    }
+}
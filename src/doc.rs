@@ -0,0 +1,128 @@
+//! The documentation of kRPC services, which comes as XML `<doc>` blocks.
+//!
+//! Shared with `build.rs`, which turns it into doc comments.
+
+use std::fmt::Write;
+
+/// The summary, remarks and return value of `doc` as markdown.
+pub fn markdown(doc: &str) -> String {
+    ["summary", "remarks", "returns"]
+        .iter()
+        .filter_map(|element| section(doc, element))
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Text of the `element` section of a kRPC `<doc>` block.
+fn section(doc: &str, element: &str) -> Option<String> {
+    let open = format!("<{}>", element);
+    let close = format!("</{}>", element);
+
+    let start = doc.find(&open)? + open.len();
+    let end = start + doc[start..].find(&close)?;
+
+    Some(text(&doc[start..end]))
+}
+
+/// The description of the parameter `name`.
+pub fn param(doc: &str, name: &str) -> Option<String> {
+    let open = format!("<param name=\"{}\">", name);
+
+    let start = doc.find(&open)? + open.len();
+    let end = start + doc[start..].find("</param>")?;
+
+    Some(text(&doc[start..end]))
+}
+
+/// Converts the inline markup of kRPC documentation to markdown.
+fn text(xml: &str) -> String {
+    let mut out = String::new();
+    let mut link = None;
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&unescape(&rest[..start]));
+
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag.trim_left_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+
+        match name {
+            "see" => if let Some(cref) = attribute(tag, "cref") {
+                // Strip the kind prefix, e.g. `M:` or `T:`
+                let target = cref.splitn(2, ':').last().unwrap_or(cref);
+                write!(out, "`{}`", target).unwrap();
+            },
+            "paramref" => if let Some(param) = attribute(tag, "name") {
+                write!(out, "`{}`", param).unwrap();
+            },
+            "c" | "math" => out.push('`'),
+            "a" if closing => {
+                if let Some(href) = link.take() {
+                    write!(out, "]({})", href).unwrap();
+                }
+            }
+            "a" => if let Some(href) = attribute(tag, "href") {
+                link = Some(href.to_string());
+                out.push('[');
+            },
+            "item" if !closing => out.push_str("\n* "),
+            "list" if closing => out.push('\n'),
+            _ => {}
+        }
+    }
+    out.push_str(&unescape(rest));
+
+    out.lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("{}=\"", name);
+
+    let start = tag.find(&key)? + key.len();
+    let end = start + tag[start..].find('"')?;
+
+    Some(&tag[start..end])
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_markdown() {
+        let doc = "<doc>\n<summary>\nThe <see cref=\"T:SpaceCenter.Vessel\" /> with the \
+                   name <paramref name=\"name\" />.\n</summary>\n<param name=\"name\">\
+                   A name &amp; nothing else.</param>\n<returns>An object.</returns>\n</doc>";
+
+        assert_eq!(
+            "The `SpaceCenter.Vessel` with the name `name`.\n\nAn object.",
+            markdown(doc)
+        );
+        assert_eq!(Some("A name & nothing else.".to_string()), param(doc, "name"));
+        assert_eq!(None, param(doc, "other"));
+    }
+}
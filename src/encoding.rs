@@ -0,0 +1,564 @@
+//! Raw kRPC value encoding.
+//!
+//! kRPC sends arguments and results as bare protobuf payloads: no field tag,
+//! varints for integers and bools, little endian for floating point numbers.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use prost::{DecodeError, Message};
+
+use schema;
+use server::{EncodeArgument, FromProcedureResult, MessageResult};
+
+pub(crate) fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let mut b = (value & 0x7F) as u8;
+
+        value >>= 7;
+        if value > 0 {
+            b |= 0x80;
+        }
+        buf.push(b);
+        if value == 0 {
+            return;
+        }
+    }
+}
+
+pub(crate) fn encode_uint64(value: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_varint(&mut buf, value);
+    buf
+}
+
+pub(crate) fn encode_bool(value: bool) -> Vec<u8> {
+    encode_uint64(value as u64)
+}
+
+pub(crate) fn encode_sint32(value: i32) -> Vec<u8> {
+    encode_uint64(((value << 1) ^ (value >> 31)) as u32 as u64)
+}
+
+pub(crate) fn encode_sint64(value: i64) -> Vec<u8> {
+    encode_uint64(((value << 1) ^ (value >> 63)) as u64)
+}
+
+pub(crate) fn encode_float(value: f32) -> Vec<u8> {
+    let bits = value.to_bits();
+    (0..4).map(|i| (bits >> (8 * i)) as u8).collect()
+}
+
+pub(crate) fn encode_double(value: f64) -> Vec<u8> {
+    let bits = value.to_bits();
+    (0..8).map(|i| (bits >> (8 * i)) as u8).collect()
+}
+
+pub(crate) fn encode_string(value: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(value.len() + 5);
+    encode_varint(&mut buf, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+    buf
+}
+
+pub(crate) fn encode_bytes(value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(value.len() + 5);
+    encode_varint(&mut buf, value.len() as u64);
+    buf.extend_from_slice(value);
+    buf
+}
+
+pub(crate) fn encode_message<M: Message>(message: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message
+        .encode(&mut buf)
+        .expect("Vec<u8> has enough capacity for any message");
+    buf
+}
+
+pub(crate) fn decode_varint(buf: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut value = 0;
+
+    for shift in 0..10 {
+        let b = match buf.first() {
+            Some(&b) => b,
+            None => return Err(DecodeError::new("truncated varint")),
+        };
+        *buf = &buf[1..];
+
+        value |= ((b & 0x7F) as u64) << (7 * shift);
+        if b <= 0x7F {
+            return Ok(value);
+        }
+    }
+
+    Err(DecodeError::new("invalid varint"))
+}
+
+pub(crate) fn decode_uint64(mut value: &[u8]) -> Result<u64, DecodeError> {
+    let decoded = decode_varint(&mut value)?;
+    if !value.is_empty() {
+        return Err(DecodeError::new("trailing bytes after varint"));
+    }
+    Ok(decoded)
+}
+
+pub(crate) fn decode_bool(value: &[u8]) -> Result<bool, DecodeError> {
+    decode_uint64(value).map(|v| v != 0)
+}
+
+pub(crate) fn decode_uint32(value: &[u8]) -> Result<u32, DecodeError> {
+    let decoded = decode_uint64(value)?;
+    if decoded > u32::max_value() as u64 {
+        return Err(DecodeError::new("uint32 out of range"));
+    }
+    Ok(decoded as u32)
+}
+
+pub(crate) fn decode_sint32(value: &[u8]) -> Result<i32, DecodeError> {
+    let decoded = decode_uint32(value)?;
+    Ok(((decoded >> 1) as i32) ^ -((decoded & 1) as i32))
+}
+
+pub(crate) fn decode_sint64(value: &[u8]) -> Result<i64, DecodeError> {
+    let decoded = decode_uint64(value)?;
+    Ok(((decoded >> 1) as i64) ^ -((decoded & 1) as i64))
+}
+
+fn decode_fixed(value: &[u8], len: usize) -> Result<u64, DecodeError> {
+    if value.len() != len {
+        return Err(DecodeError::new("invalid fixed width value"));
+    }
+    Ok(value.iter().rev().fold(0, |bits, &b| (bits << 8) | b as u64))
+}
+
+pub(crate) fn decode_float(value: &[u8]) -> Result<f32, DecodeError> {
+    decode_fixed(value, 4).map(|bits| f32::from_bits(bits as u32))
+}
+
+pub(crate) fn decode_double(value: &[u8]) -> Result<f64, DecodeError> {
+    decode_fixed(value, 8).map(f64::from_bits)
+}
+
+pub(crate) fn decode_bytes(mut value: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let len = decode_varint(&mut value)?;
+    if len != value.len() as u64 {
+        return Err(DecodeError::new("invalid length delimited value"));
+    }
+    Ok(value.to_vec())
+}
+
+pub(crate) fn decode_string(value: &[u8]) -> Result<String, DecodeError> {
+    String::from_utf8(decode_bytes(value)?)
+        .map_err(|_| DecodeError::new("invalid string value: data is not UTF-8 encoded"))
+}
+
+macro_rules! value_result {
+    ($($t:ty => $decode:path),+) => {
+        $(
+            impl FromProcedureResult for $t {
+                type Error = DecodeError;
+                fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+                    $decode(&value)
+                }
+            }
+        )+
+    };
+}
+
+value_result!(
+    f64 => decode_double,
+    f32 => decode_float,
+    i32 => decode_sint32,
+    i64 => decode_sint64,
+    u32 => decode_uint32,
+    u64 => decode_uint64,
+    bool => decode_bool,
+    String => decode_string,
+    Vec<u8> => decode_bytes
+);
+
+macro_rules! message_result {
+    ($($t:ty),+) => {
+        $(
+            impl MessageResult for $t {}
+        )+
+    };
+}
+
+message_result!(
+    schema::Status,
+    schema::Services,
+    schema::Stream,
+    schema::Event,
+    schema::ProcedureCall,
+    schema::Tuple,
+    schema::List,
+    schema::Set,
+    schema::Dictionary
+);
+
+impl FromProcedureResult for () {
+    type Error = DecodeError;
+    fn try_from(_: Vec<u8>) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+macro_rules! value_argument {
+    ($($t:ty => |$v:ident| $encode:expr),+) => {
+        $(
+            impl EncodeArgument for $t {
+                fn encode_argument(&self) -> Vec<u8> {
+                    let $v = self;
+                    $encode
+                }
+            }
+        )+
+    };
+}
+
+value_argument!(
+    f64 => |v| encode_double(*v),
+    f32 => |v| encode_float(*v),
+    i32 => |v| encode_sint32(*v),
+    i64 => |v| encode_sint64(*v),
+    u32 => |v| encode_uint64(*v as u64),
+    u64 => |v| encode_uint64(*v),
+    bool => |v| encode_bool(*v),
+    str => |v| encode_string(v),
+    String => |v| encode_string(v),
+    [u8] => |v| encode_bytes(v),
+    Vec<u8> => |v| encode_bytes(v)
+);
+
+macro_rules! message_argument {
+    ($($t:ty),+) => {
+        $(
+            impl EncodeArgument for $t {
+                fn encode_argument(&self) -> Vec<u8> {
+                    encode_message(self)
+                }
+            }
+        )+
+    };
+}
+
+message_argument!(
+    schema::Status,
+    schema::Services,
+    schema::Stream,
+    schema::Event,
+    schema::ProcedureCall,
+    schema::Tuple,
+    schema::List,
+    schema::Set,
+    schema::Dictionary
+);
+
+impl<T: FromProcedureResult<Error = DecodeError>> FromProcedureResult for Vec<T> {
+    type Error = DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+        schema::List::decode(value)?
+            .items
+            .into_iter()
+            .map(T::try_from)
+            .collect()
+    }
+}
+
+impl<T> FromProcedureResult for HashSet<T>
+where
+    T: FromProcedureResult<Error = DecodeError> + Eq + Hash,
+{
+    type Error = DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+        schema::Set::decode(value)?
+            .items
+            .into_iter()
+            .map(T::try_from)
+            .collect()
+    }
+}
+
+impl<K, V> FromProcedureResult for HashMap<K, V>
+where
+    K: FromProcedureResult<Error = DecodeError> + Eq + Hash,
+    V: FromProcedureResult<Error = DecodeError>,
+{
+    type Error = DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+        let mut map = HashMap::new();
+        for entry in schema::Dictionary::decode(value)?.entries {
+            map.insert(K::try_from(entry.key)?, V::try_from(entry.value)?);
+        }
+        Ok(map)
+    }
+}
+
+impl<T: EncodeArgument> EncodeArgument for Vec<T> {
+    fn encode_argument(&self) -> Vec<u8> {
+        encode_message(&schema::List {
+            items: self.iter().map(EncodeArgument::encode_argument).collect(),
+        })
+    }
+}
+
+impl<T: EncodeArgument + Eq + Hash> EncodeArgument for HashSet<T> {
+    fn encode_argument(&self) -> Vec<u8> {
+        encode_message(&schema::Set {
+            items: self.iter().map(EncodeArgument::encode_argument).collect(),
+        })
+    }
+}
+
+impl<K: EncodeArgument + Eq + Hash, V: EncodeArgument> EncodeArgument for HashMap<K, V> {
+    fn encode_argument(&self) -> Vec<u8> {
+        encode_message(&schema::Dictionary {
+            entries: self.iter()
+                .map(|(key, value)| schema::DictionaryEntry {
+                    key: key.encode_argument(),
+                    value: value.encode_argument(),
+                })
+                .collect(),
+        })
+    }
+}
+
+macro_rules! tuple_value {
+    ($len:expr; $($t:ident),+) => {
+        impl<$($t: FromProcedureResult<Error = DecodeError>),+> FromProcedureResult for ($($t,)+) {
+            type Error = DecodeError;
+            fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+                let tuple = schema::Tuple::decode(value)?;
+                if tuple.items.len() != $len {
+                    return Err(DecodeError::new(format!(
+                        "expected a tuple of {} items, got {}",
+                        $len,
+                        tuple.items.len()
+                    )));
+                }
+
+                let mut items = tuple.items.into_iter();
+                Ok(($($t::try_from(items.next().unwrap())?,)+))
+            }
+        }
+
+        impl<$($t: EncodeArgument),+> EncodeArgument for ($($t,)+) {
+            fn encode_argument(&self) -> Vec<u8> {
+                #[allow(non_snake_case)]
+                let ($(ref $t,)+) = *self;
+                encode_message(&schema::Tuple {
+                    items: vec![$($t.encode_argument()),+],
+                })
+            }
+        }
+    };
+}
+
+tuple_value!(1; A);
+tuple_value!(2; A, B);
+tuple_value!(3; A, B, C);
+tuple_value!(4; A, B, C, D);
+tuple_value!(5; A, B, C, D, E);
+tuple_value!(6; A, B, C, D, E, F);
+
+impl<'a, T: EncodeArgument + ?Sized> EncodeArgument for &'a T {
+    fn encode_argument(&self) -> Vec<u8> {
+        (**self).encode_argument()
+    }
+}
+
+pub(crate) fn procedure_call(
+    service: &str,
+    procedure: &str,
+    arguments: Vec<Vec<u8>>,
+) -> schema::ProcedureCall {
+    schema::ProcedureCall {
+        service: service.to_string(),
+        procedure: procedure.to_string(),
+        arguments: arguments
+            .into_iter()
+            .enumerate()
+            .map(|(position, value)| schema::Argument {
+                position: position as u32,
+                value,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use tests::run_test;
+
+    #[test]
+    fn test_encode_uint64() {
+        assert_eq!(vec![0], encode_uint64(0));
+        assert_eq!(vec![0b01111111], encode_uint64(0x7F));
+        assert_eq!(vec![0b10000000, 0b00000001], encode_uint64(0x80));
+        assert_eq!(
+            vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01],
+            encode_uint64(u64::max_value())
+        );
+    }
+
+    #[test]
+    fn test_uint64_roundtrip() {
+        run_test(
+            &any::<u64>(),
+            |&value| {
+                prop_assert_eq!(value, decode_uint64(&encode_uint64(value)).unwrap());
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_decode_uint64_invalid() {
+        assert!(decode_uint64(&[]).is_err());
+        assert!(decode_uint64(&[0x80]).is_err());
+        assert!(decode_uint64(&[0x01, 0x01]).is_err());
+        assert!(decode_uint64(&[0xFF; 11]).is_err());
+    }
+
+    #[test]
+    fn test_encode_sint32() {
+        assert_eq!(vec![0], encode_sint32(0));
+        assert_eq!(vec![1], encode_sint32(-1));
+        assert_eq!(vec![2], encode_sint32(1));
+        assert_eq!(vec![0xFE, 0xFF, 0xFF, 0xFF, 0x0F], encode_sint32(i32::max_value()));
+        assert_eq!(vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F], encode_sint32(i32::min_value()));
+    }
+
+    #[test]
+    fn test_sint_roundtrip() {
+        run_test(
+            &any::<(i32, i64)>(),
+            |&(v32, v64)| {
+                prop_assert_eq!(v32, decode_sint32(&encode_sint32(v32)).unwrap());
+                prop_assert_eq!(v64, decode_sint64(&encode_sint64(v64)).unwrap());
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_decode_string() {
+        assert_eq!("KSP", decode_string(&encode_string("KSP")).unwrap());
+        assert!(decode_string(&[4, b'K', b'S', b'P']).is_err());
+        assert!(decode_string(&[1, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_double_roundtrip() {
+        run_test(
+            &any::<f64>(),
+            |&value| {
+                let decoded = decode_double(&encode_double(value)).unwrap();
+                prop_assert_eq!(value.to_bits(), decoded.to_bits());
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_to_argument() {
+        let argument = (-2i64).to_argument(3);
+        assert_eq!(3, argument.position);
+        assert_eq!(vec![3], argument.value);
+
+        assert_eq!(encode_string("KSP"), "KSP".to_argument(0).value);
+        assert_eq!(vec![2, 0xAB, 0xCD], vec![0xABu8, 0xCD].encode_argument());
+        assert_eq!(vec![0x80, 0x01], 128u32.encode_argument());
+        assert_eq!(encode_double(1.5), 1.5f64.encode_argument());
+    }
+
+    #[test]
+    fn test_list_roundtrip() {
+        run_test(
+            &prop::collection::vec(".*", 0..10),
+            |values| {
+                let decoded = Vec::<String>::try_from(values.encode_argument()).unwrap();
+                prop_assert_eq!(values, &decoded);
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_decode_nested_collections() {
+        let position = (1.0f64, -2.0f64, 3.5f64);
+        let bounds = vec![position, (0.0, 0.0, 0.0)];
+        assert_eq!(
+            bounds,
+            Vec::<(f64, f64, f64)>::try_from(bounds.encode_argument()).unwrap()
+        );
+
+        let mut resources = HashMap::new();
+        resources.insert("LiquidFuel".to_string(), 90.0f32);
+        resources.insert("Oxidizer".to_string(), 110.0f32);
+        assert_eq!(
+            resources,
+            HashMap::<String, f32>::try_from(resources.encode_argument()).unwrap()
+        );
+
+        let tags = vec!["a".to_string(), "b".to_string()]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(tags, HashSet::<String>::try_from(tags.encode_argument()).unwrap());
+    }
+
+    #[test]
+    fn test_message_result() {
+        #[derive(Clone, PartialEq, Message)]
+        struct Position {
+            #[prost(double, tag = "1")]
+            x: f64,
+        }
+        impl MessageResult for Position {}
+
+        let position = Position { x: 2.5 };
+        assert_eq!(position, Position::try_from(encode_message(&position)).unwrap());
+    }
+
+    #[test]
+    fn test_decode_tuple_wrong_length() {
+        let pair = (1.0f64, 2.0f64).encode_argument();
+        assert!(<(f64, f64, f64)>::try_from(pair.clone()).is_err());
+        assert!(<(f64, f64)>::try_from(pair).is_ok());
+    }
+
+    #[test]
+    fn test_encode_string() {
+        assert_eq!(vec![0], encode_string(""));
+        assert_eq!(vec![3, b'K', b'S', b'P'], encode_string("KSP"));
+    }
+
+    #[test]
+    fn test_encode_float() {
+        run_test(
+            &any::<f32>(),
+            |&value| {
+                let encoded = encode_float(value);
+                prop_assert_eq!(4, encoded.len());
+
+                let bits = encoded
+                    .iter()
+                    .rev()
+                    .fold(0u32, |bits, &b| (bits << 8) | b as u32);
+                prop_assert_eq!(value.to_bits(), bits);
+
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+}
@@ -0,0 +1,744 @@
+use std::ops;
+
+use encoding;
+use schema;
+use server::{FromProcedureResult, ProcedureCall, SimpleResultError};
+
+/// A `KRPC.Expression` tree, evaluated on the server.
+///
+/// Expressions are built locally and only sent to the server, one node per
+/// call, by `Server::add_event`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    ConstantDouble(f64),
+    ConstantFloat(f32),
+    ConstantInt(i32),
+    ConstantBool(bool),
+    ConstantString(String),
+    Call(schema::ProcedureCall),
+    Parameter(String, ExpressionType),
+    Cast(Box<Expression>, ExpressionType),
+    Function(Vec<Expression>, Box<Expression>),
+    Invoke(Box<Expression>, Vec<(String, Expression)>),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+    AggregateWithSeed(Box<Expression>, Box<Expression>, Box<Expression>),
+    CreateTuple(Vec<Expression>),
+    CreateList(Vec<Expression>),
+    CreateSet(Vec<Expression>),
+    CreateDictionary(Vec<Expression>, Vec<Expression>),
+}
+
+/// The types an expression parameter or cast can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpressionType {
+    Double,
+    Float,
+    Int,
+    Bool,
+    String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Not,
+    ToList,
+    ToSet,
+    Count,
+    Sum,
+    Max,
+    Min,
+    Average,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    And,
+    Or,
+    ExclusiveOr,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    LeftShift,
+    RightShift,
+    Get,
+    Select,
+    Where,
+    Contains,
+    Aggregate,
+    Concat,
+    OrderBy,
+    All,
+    Any,
+}
+
+impl Expression {
+    /// An expression evaluating to the result of `p`.
+    pub fn call<P: ProcedureCall>(p: P) -> Self {
+        Expression::Call(p.into())
+    }
+
+    pub fn parameter<S: Into<String>>(name: S, ty: ExpressionType) -> Self {
+        Expression::Parameter(name.into(), ty)
+    }
+
+    /// A lambda with the given `Parameter` expressions, for use with `select`,
+    /// `filter`, `aggregate` and friends.
+    pub fn function(parameters: Vec<Expression>, body: Expression) -> Self {
+        Expression::Function(parameters, Box::new(body))
+    }
+
+    pub fn tuple(elements: Vec<Expression>) -> Self {
+        Expression::CreateTuple(elements)
+    }
+
+    pub fn list(values: Vec<Expression>) -> Self {
+        Expression::CreateList(values)
+    }
+
+    pub fn set(values: Vec<Expression>) -> Self {
+        Expression::CreateSet(values)
+    }
+
+    pub fn dictionary(keys: Vec<Expression>, values: Vec<Expression>) -> Self {
+        Expression::CreateDictionary(keys, values)
+    }
+
+    pub fn cast(self, ty: ExpressionType) -> Self {
+        Expression::Cast(Box::new(self), ty)
+    }
+
+    /// Calls a `function` expression with arguments given by parameter name.
+    pub fn invoke(self, arguments: Vec<(String, Expression)>) -> Self {
+        Expression::Invoke(Box::new(self), arguments)
+    }
+
+    pub fn equal<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Equal, other)
+    }
+
+    pub fn not_equal<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::NotEqual, other)
+    }
+
+    pub fn greater_than<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::GreaterThan, other)
+    }
+
+    pub fn greater_than_or_equal<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::GreaterThanOrEqual, other)
+    }
+
+    pub fn less_than<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::LessThan, other)
+    }
+
+    pub fn less_than_or_equal<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::LessThanOrEqual, other)
+    }
+
+    pub fn and<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::And, other)
+    }
+
+    pub fn or<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Or, other)
+    }
+
+    pub fn xor<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::ExclusiveOr, other)
+    }
+
+    pub fn pow<E: Into<Expression>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Power, other)
+    }
+
+    pub fn get<E: Into<Expression>>(self, index: E) -> Self {
+        self.binary(BinaryOperator::Get, index)
+    }
+
+    pub fn select(self, function: Expression) -> Self {
+        self.binary(BinaryOperator::Select, function)
+    }
+
+    /// `Where` on the server: keeps the elements `function` returns true for.
+    pub fn filter(self, function: Expression) -> Self {
+        self.binary(BinaryOperator::Where, function)
+    }
+
+    pub fn contains<E: Into<Expression>>(self, value: E) -> Self {
+        self.binary(BinaryOperator::Contains, value)
+    }
+
+    pub fn aggregate(self, function: Expression) -> Self {
+        self.binary(BinaryOperator::Aggregate, function)
+    }
+
+    pub fn aggregate_with_seed<E: Into<Expression>>(self, seed: E, function: Expression) -> Self {
+        Expression::AggregateWithSeed(Box::new(self), Box::new(seed.into()), Box::new(function))
+    }
+
+    pub fn concat(self, other: Expression) -> Self {
+        self.binary(BinaryOperator::Concat, other)
+    }
+
+    pub fn order_by(self, key: Expression) -> Self {
+        self.binary(BinaryOperator::OrderBy, key)
+    }
+
+    pub fn all(self, predicate: Expression) -> Self {
+        self.binary(BinaryOperator::All, predicate)
+    }
+
+    pub fn any(self, predicate: Expression) -> Self {
+        self.binary(BinaryOperator::Any, predicate)
+    }
+
+    pub fn to_list(self) -> Self {
+        self.unary(UnaryOperator::ToList)
+    }
+
+    pub fn to_set(self) -> Self {
+        self.unary(UnaryOperator::ToSet)
+    }
+
+    pub fn count(self) -> Self {
+        self.unary(UnaryOperator::Count)
+    }
+
+    pub fn sum(self) -> Self {
+        self.unary(UnaryOperator::Sum)
+    }
+
+    pub fn max(self) -> Self {
+        self.unary(UnaryOperator::Max)
+    }
+
+    pub fn min(self) -> Self {
+        self.unary(UnaryOperator::Min)
+    }
+
+    pub fn average(self) -> Self {
+        self.unary(UnaryOperator::Average)
+    }
+
+    fn unary(self, operator: UnaryOperator) -> Self {
+        Expression::Unary(operator, Box::new(self))
+    }
+
+    fn binary<E: Into<Expression>>(self, operator: BinaryOperator, other: E) -> Self {
+        Expression::Binary(operator, Box::new(self), Box::new(other.into()))
+    }
+
+    /// Flattens the tree into the calls creating it on the server, children
+    /// first. The last instruction creates the root.
+    pub(crate) fn compile(&self) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        self.compile_into(&mut instructions);
+        instructions
+    }
+
+    fn compile_into(&self, instructions: &mut Vec<Instruction>) -> usize {
+        use self::Argument::*;
+
+        let (procedure, arguments) = match *self {
+            Expression::ConstantDouble(v) => {
+                ("Expression_static_ConstantDouble", vec![Value(encoding::encode_double(v))])
+            }
+            Expression::ConstantFloat(v) => {
+                ("Expression_static_ConstantFloat", vec![Value(encoding::encode_float(v))])
+            }
+            Expression::ConstantInt(v) => {
+                ("Expression_static_ConstantInt", vec![Value(encoding::encode_sint32(v))])
+            }
+            Expression::ConstantBool(v) => {
+                ("Expression_static_ConstantBool", vec![Value(encoding::encode_bool(v))])
+            }
+            Expression::ConstantString(ref v) => {
+                ("Expression_static_ConstantString", vec![Value(encoding::encode_string(v))])
+            }
+            Expression::Call(ref call) => {
+                ("Expression_static_Call", vec![Value(encoding::encode_message(call))])
+            }
+            Expression::Parameter(ref name, ty) => (
+                "Expression_static_Parameter",
+                vec![
+                    Value(encoding::encode_string(name)),
+                    Handle(ty.compile_into(instructions)),
+                ],
+            ),
+            Expression::Cast(ref arg, ty) => (
+                "Expression_static_Cast",
+                vec![
+                    Handle(arg.compile_into(instructions)),
+                    Handle(ty.compile_into(instructions)),
+                ],
+            ),
+            Expression::Function(ref parameters, ref body) => (
+                "Expression_static_Function",
+                vec![
+                    List(compile_all(parameters, instructions)),
+                    Handle(body.compile_into(instructions)),
+                ],
+            ),
+            Expression::Invoke(ref function, ref args) => (
+                "Expression_static_Invoke",
+                vec![
+                    Handle(function.compile_into(instructions)),
+                    Dictionary(
+                        args.iter()
+                            .map(|&(ref name, ref arg)| {
+                                (encoding::encode_string(name), arg.compile_into(instructions))
+                            })
+                            .collect(),
+                    ),
+                ],
+            ),
+            Expression::Unary(operator, ref arg) => {
+                (operator.procedure(), vec![Handle(arg.compile_into(instructions))])
+            }
+            Expression::Binary(operator, ref arg0, ref arg1) => (
+                operator.procedure(),
+                vec![
+                    Handle(arg0.compile_into(instructions)),
+                    Handle(arg1.compile_into(instructions)),
+                ],
+            ),
+            Expression::AggregateWithSeed(ref arg, ref seed, ref function) => (
+                "Expression_static_AggregateWithSeed",
+                vec![
+                    Handle(arg.compile_into(instructions)),
+                    Handle(seed.compile_into(instructions)),
+                    Handle(function.compile_into(instructions)),
+                ],
+            ),
+            Expression::CreateTuple(ref elements) => (
+                "Expression_static_CreateTuple",
+                vec![List(compile_all(elements, instructions))],
+            ),
+            Expression::CreateList(ref values) => (
+                "Expression_static_CreateList",
+                vec![List(compile_all(values, instructions))],
+            ),
+            Expression::CreateSet(ref values) => (
+                "Expression_static_CreateSet",
+                vec![Set(compile_all(values, instructions))],
+            ),
+            Expression::CreateDictionary(ref keys, ref values) => (
+                "Expression_static_CreateDictionary",
+                vec![
+                    List(compile_all(keys, instructions)),
+                    List(compile_all(values, instructions)),
+                ],
+            ),
+        };
+
+        push(
+            instructions,
+            Instruction {
+                procedure,
+                arguments,
+            },
+        )
+    }
+}
+
+fn push(instructions: &mut Vec<Instruction>, instruction: Instruction) -> usize {
+    // Identical subtrees share one server side object. This is required for
+    // parameters: a function body has to refer to the very same parameter
+    // objects as the function signature.
+    match instructions.iter().position(|i| *i == instruction) {
+        Some(i) => i,
+        None => {
+            instructions.push(instruction);
+            instructions.len() - 1
+        }
+    }
+}
+
+/// Groups the indices of `instructions` by their height in the tree. Each
+/// group only refers to the results of earlier ones, so it can be sent as a
+/// single batch.
+pub(crate) fn levels(instructions: &[Instruction]) -> Vec<Vec<usize>> {
+    let mut heights: Vec<usize> = Vec::with_capacity(instructions.len());
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        let height = instruction
+            .handles()
+            .into_iter()
+            .map(|handle| heights[handle] + 1)
+            .max()
+            .unwrap_or(0);
+        heights.push(height);
+
+        if levels.len() == height {
+            levels.push(Vec::new());
+        }
+        levels[height].push(i);
+    }
+    levels
+}
+
+fn compile_all(expressions: &[Expression], instructions: &mut Vec<Instruction>) -> Vec<usize> {
+    expressions
+        .iter()
+        .map(|e| e.compile_into(instructions))
+        .collect()
+}
+
+impl ExpressionType {
+    fn compile_into(&self, instructions: &mut Vec<Instruction>) -> usize {
+        let procedure = match *self {
+            ExpressionType::Double => "Type_static_Double",
+            ExpressionType::Float => "Type_static_Float",
+            ExpressionType::Int => "Type_static_Int",
+            ExpressionType::Bool => "Type_static_Bool",
+            ExpressionType::String => "Type_static_String",
+        };
+
+        push(
+            instructions,
+            Instruction {
+                procedure,
+                arguments: Vec::new(),
+            },
+        )
+    }
+}
+
+impl UnaryOperator {
+    fn procedure(&self) -> &'static str {
+        match *self {
+            UnaryOperator::Not => "Expression_static_Not",
+            UnaryOperator::ToList => "Expression_static_ToList",
+            UnaryOperator::ToSet => "Expression_static_ToSet",
+            UnaryOperator::Count => "Expression_static_Count",
+            UnaryOperator::Sum => "Expression_static_Sum",
+            UnaryOperator::Max => "Expression_static_Max",
+            UnaryOperator::Min => "Expression_static_Min",
+            UnaryOperator::Average => "Expression_static_Average",
+        }
+    }
+}
+
+impl BinaryOperator {
+    fn procedure(&self) -> &'static str {
+        match *self {
+            BinaryOperator::Equal => "Expression_static_Equal",
+            BinaryOperator::NotEqual => "Expression_static_NotEqual",
+            BinaryOperator::GreaterThan => "Expression_static_GreaterThan",
+            BinaryOperator::GreaterThanOrEqual => "Expression_static_GreaterThanOrEqual",
+            BinaryOperator::LessThan => "Expression_static_LessThan",
+            BinaryOperator::LessThanOrEqual => "Expression_static_LessThanOrEqual",
+            BinaryOperator::And => "Expression_static_And",
+            BinaryOperator::Or => "Expression_static_Or",
+            BinaryOperator::ExclusiveOr => "Expression_static_ExclusiveOr",
+            BinaryOperator::Add => "Expression_static_Add",
+            BinaryOperator::Subtract => "Expression_static_Subtract",
+            BinaryOperator::Multiply => "Expression_static_Multiply",
+            BinaryOperator::Divide => "Expression_static_Divide",
+            BinaryOperator::Modulo => "Expression_static_Modulo",
+            BinaryOperator::Power => "Expression_static_Power",
+            BinaryOperator::LeftShift => "Expression_static_LeftShift",
+            BinaryOperator::RightShift => "Expression_static_RightShift",
+            BinaryOperator::Get => "Expression_static_Get",
+            BinaryOperator::Select => "Expression_static_Select",
+            BinaryOperator::Where => "Expression_static_Where",
+            BinaryOperator::Contains => "Expression_static_Contains",
+            BinaryOperator::Aggregate => "Expression_static_Aggregate",
+            BinaryOperator::Concat => "Expression_static_Concat",
+            BinaryOperator::OrderBy => "Expression_static_OrderBy",
+            BinaryOperator::All => "Expression_static_All",
+            BinaryOperator::Any => "Expression_static_Any",
+        }
+    }
+}
+
+impl From<f64> for Expression {
+    fn from(v: f64) -> Self {
+        Expression::ConstantDouble(v)
+    }
+}
+
+impl From<f32> for Expression {
+    fn from(v: f32) -> Self {
+        Expression::ConstantFloat(v)
+    }
+}
+
+impl From<i32> for Expression {
+    fn from(v: i32) -> Self {
+        Expression::ConstantInt(v)
+    }
+}
+
+impl From<bool> for Expression {
+    fn from(v: bool) -> Self {
+        Expression::ConstantBool(v)
+    }
+}
+
+impl<'a> From<&'a str> for Expression {
+    fn from(v: &'a str) -> Self {
+        Expression::ConstantString(v.to_string())
+    }
+}
+
+impl From<String> for Expression {
+    fn from(v: String) -> Self {
+        Expression::ConstantString(v)
+    }
+}
+
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $operator:ident) => {
+        impl<E: Into<Expression>> ops::$trait<E> for Expression {
+            type Output = Expression;
+
+            fn $method(self, other: E) -> Expression {
+                self.binary(BinaryOperator::$operator, other)
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, Add);
+binary_operator!(Sub, sub, Subtract);
+binary_operator!(Mul, mul, Multiply);
+binary_operator!(Div, div, Divide);
+binary_operator!(Rem, rem, Modulo);
+binary_operator!(Shl, shl, LeftShift);
+binary_operator!(Shr, shr, RightShift);
+
+impl ops::Not for Expression {
+    type Output = Expression;
+
+    fn not(self) -> Expression {
+        self.unary(UnaryOperator::Not)
+    }
+}
+
+/// One call of the flattened expression tree. Arguments refer to the results
+/// of earlier instructions by index.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Instruction {
+    procedure: &'static str,
+    arguments: Vec<Argument>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Argument {
+    Value(Vec<u8>),
+    Handle(usize),
+    List(Vec<usize>),
+    Set(Vec<usize>),
+    Dictionary(Vec<(Vec<u8>, usize)>),
+}
+
+impl Instruction {
+    /// The indices of the instructions whose results this one uses.
+    fn handles(&self) -> Vec<usize> {
+        self.arguments
+            .iter()
+            .flat_map(|argument| match *argument {
+                Argument::Value(_) => Vec::new(),
+                Argument::Handle(i) => vec![i],
+                Argument::List(ref items) | Argument::Set(ref items) => items.clone(),
+                Argument::Dictionary(ref entries) => entries.iter().map(|&(_, i)| i).collect(),
+            })
+            .collect()
+    }
+
+    /// The call for this instruction, given the handles created by all
+    /// previous instructions.
+    pub(crate) fn to_call(&self, handles: &[RemoteHandle]) -> ExpressionCall {
+        let handle = |i: usize| encoding::encode_uint64(handles[i].0);
+
+        let arguments = self.arguments
+            .iter()
+            .map(|argument| match *argument {
+                Argument::Value(ref v) => v.clone(),
+                Argument::Handle(i) => handle(i),
+                Argument::List(ref items) => encoding::encode_message(&schema::List {
+                    items: items.iter().map(|&i| handle(i)).collect(),
+                }),
+                Argument::Set(ref items) => encoding::encode_message(&schema::Set {
+                    items: items.iter().map(|&i| handle(i)).collect(),
+                }),
+                Argument::Dictionary(ref entries) => {
+                    encoding::encode_message(&schema::Dictionary {
+                        entries: entries
+                            .iter()
+                            .map(|&(ref key, i)| schema::DictionaryEntry {
+                                key: key.clone(),
+                                value: handle(i),
+                            })
+                            .collect(),
+                    })
+                }
+            })
+            .collect();
+
+        ExpressionCall {
+            procedure: self.procedure,
+            arguments,
+        }
+    }
+}
+
+/// Object id of a `KRPC.Expression` or `KRPC.Type` on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RemoteHandle(pub(crate) u64);
+
+impl FromProcedureResult for RemoteHandle {
+    type Error = ::prost::DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, ::prost::DecodeError> {
+        encoding::decode_uint64(&value).map(RemoteHandle)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ExpressionCall {
+    procedure: &'static str,
+    arguments: Vec<Vec<u8>>,
+}
+
+impl ProcedureCall for ExpressionCall {
+    type Result = RemoteHandle;
+    type Error = SimpleResultError;
+}
+
+impl From<ExpressionCall> for schema::ProcedureCall {
+    fn from(p: ExpressionCall) -> Self {
+        encoding::procedure_call("KRPC", p.procedure, p.arguments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::krpc;
+
+    fn procedures(expression: &Expression) -> Vec<&'static str> {
+        expression
+            .compile()
+            .into_iter()
+            .map(|i| i.procedure)
+            .collect()
+    }
+
+    #[test]
+    fn test_compile_children_first() {
+        let expression = Expression::call(krpc::GetStatus).greater_than(10_000.0);
+
+        assert_eq!(
+            vec![
+                "Expression_static_Call",
+                "Expression_static_ConstantDouble",
+                "Expression_static_GreaterThan",
+            ],
+            procedures(&expression)
+        );
+
+        let instructions = expression.compile();
+        assert_eq!(
+            vec![Argument::Handle(0), Argument::Handle(1)],
+            instructions[2].arguments
+        );
+    }
+
+    #[test]
+    fn test_compile_operators() {
+        let expression = !(Expression::from(1) + 2).equal(3).or(false);
+
+        assert_eq!(
+            vec![
+                "Expression_static_ConstantInt",
+                "Expression_static_ConstantInt",
+                "Expression_static_Add",
+                "Expression_static_ConstantInt",
+                "Expression_static_Equal",
+                "Expression_static_ConstantBool",
+                "Expression_static_Or",
+                "Expression_static_Not",
+            ],
+            procedures(&expression)
+        );
+    }
+
+    #[test]
+    fn test_compile_function() {
+        let x = Expression::parameter("x", ExpressionType::Double);
+        let list = Expression::list(vec![1.0.into(), 2.0.into()]);
+        let function = Expression::function(vec![x.clone()], x * 2.0);
+        let expression = list.select(function).sum();
+
+        let instructions = expression.compile();
+        assert_eq!(
+            vec![
+                "Expression_static_ConstantDouble",
+                "Expression_static_ConstantDouble",
+                "Expression_static_CreateList",
+                "Type_static_Double",
+                "Expression_static_Parameter",
+                "Expression_static_Multiply",
+                "Expression_static_Function",
+                "Expression_static_Select",
+                "Expression_static_Sum",
+            ],
+            instructions.iter().map(|i| i.procedure).collect::<Vec<_>>()
+        );
+        // The body uses the parameter object of the signature
+        assert_eq!(
+            vec![Argument::Handle(4), Argument::Handle(1)],
+            instructions[5].arguments
+        );
+        assert_eq!(
+            vec![Argument::List(vec![4]), Argument::Handle(5)],
+            instructions[6].arguments
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        let status = Expression::call(krpc::GetStatus);
+        let expression = status.clone().greater_than(1.0).and(status.less_than(2.0));
+
+        // Call, 1.0, GreaterThan, 2.0, LessThan, And
+        assert_eq!(
+            vec![vec![0, 1, 3], vec![2, 4], vec![5]],
+            levels(&expression.compile())
+        );
+    }
+
+    #[test]
+    fn test_instruction_to_call() {
+        let expression = Expression::set(vec![true.into(), false.into()]);
+        let instructions = expression.compile();
+
+        let handles = vec![RemoteHandle(7), RemoteHandle(300)];
+        let call: schema::ProcedureCall = instructions[2].to_call(&handles).into();
+
+        assert_eq!("KRPC", call.service);
+        assert_eq!("Expression_static_CreateSet", call.procedure);
+        assert_eq!(
+            vec![schema::Argument {
+                position: 0,
+                value: encoding::encode_message(&schema::Set {
+                    items: vec![vec![7], vec![0xAC, 0x02]],
+                }),
+            }],
+            call.arguments
+        );
+    }
+}
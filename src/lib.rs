@@ -0,0 +1,60 @@
+#![cfg_attr(feature = "async", feature(proc_macro, generators, pin))]
+#![cfg_attr(feature = "async", feature(proc_macro_non_items))]
+// Expressions and stream subscriptions are partly only used by `Server`
+#![cfg_attr(not(feature = "async"), allow(dead_code))]
+
+#[macro_use]
+extern crate failure;
+
+extern crate base64;
+extern crate bytes;
+#[cfg(feature = "async")]
+#[macro_use]
+extern crate futures_await as futures;
+#[cfg(not(feature = "async"))]
+#[macro_use]
+extern crate futures;
+extern crate prost;
+#[macro_use]
+extern crate prost_derive;
+#[cfg(feature = "cli")]
+extern crate rustyline;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio;
+extern crate tokio_io;
+extern crate tokio_tungstenite;
+extern crate tungstenite;
+extern crate url;
+
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+
+pub mod batch;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod client;
+pub mod connection;
+pub mod discovery;
+mod doc;
+mod encoding;
+pub mod expression;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod object;
+#[cfg(feature = "async")]
+pub mod reconnect;
+pub mod schema;
+pub mod server;
+pub mod services;
+pub mod stream;
+pub mod sync;
+pub mod value;
+
+#[cfg(test)]
+mod tests;
+
+pub use value::Value;
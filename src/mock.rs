@@ -0,0 +1,509 @@
+//! A kRPC server for testing clients without the game. Needs the `mock`
+//! feature.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::fs::File;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use prost::Message;
+use serde_json;
+
+use connection::{BlockingConnection, ConnectionError};
+use encoding;
+use schema;
+
+type Handler = Arc<Fn(&schema::ProcedureCall) -> Result<Vec<u8>, schema::Error> + Send + Sync>;
+
+/// A kRPC server on a local port, which speaks the real protocol but answers
+/// calls with handlers registered by the test.
+///
+/// Without a handler, the procedures of the `KRPC` service for the status,
+/// the services and streams behave like on a real server, and any other
+/// procedure fails. Calls by procedure id are understood as well. Stream
+/// values are only sent when pushed by the test, to the client that added the
+/// stream.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    services: schema::Services,
+    handlers: HashMap<(String, String), Handler>,
+    calls: Vec<schema::ProcedureCall>,
+    streams: HashMap<u64, schema::ProcedureCall>,
+    /// The client identifiers of the clients that added the streams.
+    owners: HashMap<u64, Vec<u8>>,
+    next_stream: u64,
+    clients: Vec<Vec<u8>>,
+    sockets: Vec<TcpStream>,
+    /// The stream connections, with the identifiers of their clients.
+    updates: Vec<(Vec<u8>, BlockingConnection<TcpStream>)>,
+    closed: bool,
+}
+
+impl MockServer {
+    /// Starts a server for `services` on a free port.
+    pub fn start(services: schema::Services) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let state = Arc::new(Mutex::new(State {
+            services,
+            ..Default::default()
+        }));
+        {
+            let state = state.clone();
+            thread::spawn(move || accept(listener, state));
+        }
+
+        Ok(MockServer { addr, state })
+    }
+
+    /// Starts a server for the services described in a JSON file like
+    /// `services.json`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let services = serde_json::from_reader(File::open(path)?)?;
+        Self::start(services)
+    }
+
+    /// The address to connect RPC and stream connections to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Answers calls of `service.procedure` with `handler`, which gets the
+    /// call and returns the encoded result or an error.
+    pub fn handle<F>(&self, service: &str, procedure: &str, handler: F)
+    where
+        F: Fn(&schema::ProcedureCall) -> Result<Vec<u8>, schema::Error> + Send + Sync + 'static,
+    {
+        self.state
+            .lock()
+            .unwrap()
+            .handlers
+            .insert((service.to_string(), procedure.to_string()), Arc::new(handler));
+    }
+
+    /// Answers every call of `service.procedure` with the encoded `value`.
+    pub fn respond(&self, service: &str, procedure: &str, value: Vec<u8>) {
+        self.handle(service, procedure, move |_| Ok(value.clone()));
+    }
+
+    /// Fails every call of `service.procedure` with `error`.
+    pub fn fail(&self, service: &str, procedure: &str, error: schema::Error) {
+        self.handle(service, procedure, move |_| Err(error.clone()));
+    }
+
+    /// All calls received so far, in order.
+    pub fn calls(&self) -> Vec<schema::ProcedureCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// The streams that were added and not removed yet, by id.
+    pub fn streams(&self) -> HashMap<u64, schema::ProcedureCall> {
+        self.state.lock().unwrap().streams.clone()
+    }
+
+    /// Sends `value` as the new value of the stream `id` to the client that
+    /// added it.
+    pub fn push(&self, id: u64, value: Vec<u8>) {
+        self.push_update(schema::StreamUpdate {
+            results: vec![schema::StreamResult {
+                id,
+                result: Some(schema::ProcedureResult { error: None, value }),
+            }],
+        });
+    }
+
+    /// Sends the results of `update` to the stream connections of the clients
+    /// that added their streams. Results of unknown streams are not sent, and
+    /// clients that have gone away are dropped.
+    pub fn push_update(&self, update: schema::StreamUpdate) {
+        let mut state = self.state.lock().unwrap();
+        let updates = state.updates.drain(..).collect::<Vec<_>>();
+        for (client, mut connection) in updates {
+            let results: Vec<_> = update
+                .results
+                .iter()
+                .filter(|result| state.owners.get(&result.id) == Some(&client))
+                .cloned()
+                .collect();
+
+            if results.is_empty() || connection.send(&schema::StreamUpdate { results }).is_ok() {
+                state.updates.push((client, connection));
+            }
+        }
+    }
+
+    /// Closes the connections of all clients, like a crashing game would.
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().disconnect();
+    }
+}
+
+impl Debug for MockServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("MockServer")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            state.disconnect();
+        }
+
+        // Wakes up the accepting thread, which then sees the server is closed
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl State {
+    /// Fills in the names of a call made by ids.
+    fn name(&self, call: &mut schema::ProcedureCall) {
+        if call.service_id == 0 || call.procedure_id == 0 {
+            return;
+        }
+
+        if let Some(service) = self.services.services.get(call.service_id as usize - 1) {
+            call.service = service.name.clone();
+            if let Some(procedure) = service.procedures.get(call.procedure_id as usize - 1) {
+                call.procedure = procedure.name.clone();
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        for socket in self.sockets.drain(..) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        self.updates.clear();
+    }
+
+    fn handshake(&mut self, request: &schema::ConnectionRequest) -> schema::ConnectionResponse {
+        use schema::connection_request::Type;
+        use schema::connection_response::Status;
+
+        let response = |status: Status, message: &str, client_identifier| {
+            schema::ConnectionResponse {
+                status: status.into(),
+                message: message.to_string(),
+                client_identifier,
+            }
+        };
+
+        match Type::from_i32(request.type_) {
+            Some(Type::Rpc) => {
+                let client_identifier = encoding::encode_uint64(self.clients.len() as u64 + 1);
+                self.clients.push(client_identifier.clone());
+                response(Status::Ok, "", client_identifier)
+            }
+            Some(Type::Stream) if self.clients.contains(&request.client_identifier) => {
+                response(Status::Ok, "", Vec::new())
+            }
+            Some(Type::Stream) => response(
+                Status::MalformedMessage,
+                "Unknown client identifier",
+                Vec::new(),
+            ),
+            None => response(Status::WrongType, "Unknown connection type", Vec::new()),
+        }
+    }
+
+    /// The procedures a test needs to get clients going, called by `client`.
+    fn builtin(
+        &mut self,
+        client: &[u8],
+        call: &schema::ProcedureCall,
+    ) -> Result<Vec<u8>, schema::Error> {
+        let argument = |position| {
+            call.arguments
+                .iter()
+                .find(|a| a.position == position)
+                .map(|a| &a.value[..])
+                .ok_or_else(|| error(&format!("Missing argument {}", position)))
+        };
+
+        match (&call.service[..], &call.procedure[..]) {
+            ("KRPC", "GetStatus") => Ok(encoding::encode_message(&schema::Status {
+                version: "mock".to_string(),
+                ..Default::default()
+            })),
+            ("KRPC", "GetServices") => Ok(encoding::encode_message(&self.services)),
+            ("KRPC", "AddStream") => {
+                let streamed = schema::ProcedureCall::decode(argument(0)?)
+                    .map_err(|e| error(&e.to_string()))?;
+                self.next_stream += 1;
+                self.streams.insert(self.next_stream, streamed);
+                self.owners.insert(self.next_stream, client.to_vec());
+
+                Ok(encoding::encode_message(&schema::Stream {
+                    id: self.next_stream,
+                }))
+            }
+            ("KRPC", "AddEvent") => {
+                self.next_stream += 1;
+                self.owners.insert(self.next_stream, client.to_vec());
+                Ok(encoding::encode_message(&schema::Event {
+                    stream: Some(schema::Stream {
+                        id: self.next_stream,
+                    }),
+                }))
+            }
+            ("KRPC", "RemoveStream") => {
+                let id = encoding::decode_uint64(argument(0)?)
+                    .map_err(|e| error(&e.to_string()))?;
+                self.streams.remove(&id);
+                self.owners.remove(&id);
+                Ok(Vec::new())
+            }
+            ("KRPC", "StartStream") | ("KRPC", "SetStreamRate") => Ok(Vec::new()),
+            (service, procedure) => Err(error(&format!(
+                "Procedure {}.{} not found",
+                service, procedure
+            ))),
+        }
+    }
+}
+
+fn error(description: &str) -> schema::Error {
+    schema::Error {
+        description: description.to_string(),
+        ..Default::default()
+    }
+}
+
+fn accept(listener: TcpListener, state: Arc<Mutex<State>>) {
+    for socket in listener.incoming() {
+        if state.lock().unwrap().closed {
+            return;
+        }
+
+        if let Ok(socket) = socket {
+            let state = state.clone();
+            thread::spawn(move || serve(socket, state));
+        }
+    }
+}
+
+/// Does the handshake of a new connection and then answers its requests
+/// until it is closed.
+fn serve(socket: TcpStream, state: Arc<Mutex<State>>) -> Result<(), ConnectionError> {
+    use schema::connection_request::Type;
+    use schema::connection_response::Status;
+
+    state.lock().unwrap().sockets.push(socket.try_clone()?);
+    let mut connection = BlockingConnection::new(socket);
+
+    let request: schema::ConnectionRequest = connection.receive()?;
+    let client = {
+        // Stream connections are added before the client hears back, so no
+        // update pushed after the handshake gets lost
+        let mut state = state.lock().unwrap();
+        let response = state.handshake(&request);
+        connection.send(&response)?;
+
+        if response.status != Status::Ok as i32 {
+            return Ok(());
+        }
+        if request.type_ == Type::Stream as i32 {
+            state.updates.push((request.client_identifier, connection));
+            return Ok(());
+        }
+        response.client_identifier
+    };
+
+    loop {
+        let request: schema::Request = connection.receive()?;
+        let results = request
+            .calls
+            .into_iter()
+            .map(|call| match call_procedure(&state, &client, call) {
+                Ok(value) => schema::ProcedureResult { error: None, value },
+                Err(e) => schema::ProcedureResult {
+                    error: Some(e),
+                    value: Vec::new(),
+                },
+            })
+            .collect();
+
+        connection.send(&schema::Response {
+            error: None,
+            results,
+        })?;
+    }
+}
+
+fn call_procedure(
+    state: &Mutex<State>,
+    client: &[u8],
+    mut call: schema::ProcedureCall,
+) -> Result<Vec<u8>, schema::Error> {
+    let handler = {
+        let mut state = state.lock().unwrap();
+        state.calls.push(call.clone());
+        state.name(&mut call);
+
+        let key = (call.service.clone(), call.procedure.clone());
+        match state.handlers.get(&key) {
+            Some(handler) => handler.clone(),
+            None => return state.builtin(client, &call),
+        }
+    };
+
+    // Without the lock, so handlers may take their time
+    handler(&call)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use client::ClientError;
+    use connection::ConnectionBuilder;
+    use discovery::{ProcedureIds, ServiceTree};
+    use services::krpc;
+    use sync::SyncClient;
+
+    #[test]
+    fn test_handlers_and_errors() {
+        let server = MockServer::start(schema::Services::default()).unwrap();
+        let mut client = SyncClient::connect(server.addr()).unwrap();
+
+        assert_eq!("mock", client.call(krpc::GetStatus).unwrap().version);
+
+        let call = encoding::procedure_call("SpaceCenter", "get_UT", Vec::new());
+        let request = schema::Request {
+            calls: vec![call.clone()],
+        };
+
+        let response = client.request(request.clone()).unwrap();
+        assert!(response.results[0].error.is_some());
+
+        server.respond("SpaceCenter", "get_UT", encoding::encode_double(42.0));
+        let response = client.request(request.clone()).unwrap();
+        assert_eq!(encoding::encode_double(42.0), response.results[0].value);
+
+        let error = schema::Error {
+            service: "KRPC".to_string(),
+            name: "InvalidOperationException".to_string(),
+            description: "No active vessel".to_string(),
+            stack_trace: String::new(),
+        };
+        server.fail("KRPC", "GetStatus", error.clone());
+        match client.call(krpc::GetStatus) {
+            Err(ClientError::Procedure(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let calls = server.calls();
+        assert_eq!(4, calls.len());
+        assert_eq!(call, calls[1]);
+    }
+
+    #[test]
+    fn test_services_and_streams() {
+        let server = MockServer::from_file("services.json").unwrap();
+        let mut client = SyncClient::connect(server.addr()).unwrap();
+
+        let services = client.call(krpc::GetServices).unwrap();
+        assert!(services.services.iter().any(|s| s.name == "SpaceCenter"));
+
+        let mut stream = client.add_stream(krpc::GetStatus).unwrap();
+        assert_eq!(
+            vec![(stream.id(), schema::ProcedureCall::from(krpc::GetStatus))],
+            server.streams().into_iter().collect::<Vec<_>>()
+        );
+
+        let status = schema::Status {
+            version: "1.0".to_string(),
+            ..Default::default()
+        };
+        server.push(stream.id(), encoding::encode_message(&status));
+        assert_eq!(status, stream.next().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_updates_per_client() {
+        let server = MockServer::start(schema::Services::default()).unwrap();
+        let mut client = SyncClient::connect(server.addr()).unwrap();
+        let mut stream = client.add_stream(krpc::GetStatus).unwrap();
+
+        // A second client, which reads its updates itself
+        let tcp = TcpStream::connect(server.addr()).unwrap();
+        let mut connection = ConnectionBuilder::new().initialize_blocking(tcp).unwrap();
+        let tcp = TcpStream::connect(server.addr()).unwrap();
+        let client_identifier = connection.client_identifier().to_vec();
+        let mut updates = ConnectionBuilder::new()
+            .initialize_blocking_stream(tcp, client_identifier)
+            .unwrap();
+
+        let add = krpc::AddStream {
+            call: krpc::GetStatus.into(),
+            start: true,
+        };
+        connection
+            .send(&schema::Request {
+                calls: vec![add.into()],
+            })
+            .unwrap();
+        let response: schema::Response = connection.receive().unwrap();
+        let id = schema::Stream::decode(&response.results[0].value[..]).unwrap().id;
+
+        let status = schema::Status {
+            version: "1.0".to_string(),
+            ..Default::default()
+        };
+        server.push(stream.id(), encoding::encode_message(&status));
+        server.push(id, encoding::encode_message(&status));
+
+        assert_eq!(status, stream.next().unwrap().unwrap());
+        let update: schema::StreamUpdate = updates.receive().unwrap();
+        assert_eq!(vec![id], update.results.iter().map(|r| r.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_resolved_calls() {
+        let server = MockServer::from_file("services.json").unwrap();
+        let mut client = SyncClient::connect(server.addr()).unwrap();
+
+        let services = ServiceTree::new(client.call(krpc::GetServices).unwrap());
+        client.resolve_calls(&services);
+        assert_eq!("mock", client.call(krpc::GetStatus).unwrap().version);
+
+        let (service_id, procedure_id) = ProcedureIds::new(&services)
+            .get("KRPC", "GetStatus")
+            .unwrap();
+        let call = server.calls().pop().unwrap();
+        assert_eq!(("", ""), (&call.service[..], &call.procedure[..]));
+        assert_eq!((service_id, procedure_id), (call.service_id, call.procedure_id));
+    }
+
+    #[test]
+    fn test_rejected_handshakes() {
+        let server = MockServer::start(schema::Services::default()).unwrap();
+
+        let tcp = TcpStream::connect(server.addr()).unwrap();
+        match ConnectionBuilder::new().initialize_blocking_stream(tcp, vec![9]) {
+            Err(ConnectionError::Rejected { status, .. }) => assert_eq!(
+                schema::connection_response::Status::MalformedMessage,
+                status
+            ),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        let tcp = TcpStream::connect(server.addr()).unwrap();
+        ConnectionBuilder::new().initialize_blocking(tcp).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! Handles of objects that live on the kRPC server.
+
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use prost::DecodeError;
+
+use encoding;
+use server::{EncodeArgument, FromProcedureResult};
+
+/// A kRPC class, e.g. `SpaceCenter.Vessel`. Implemented by the marker types
+/// generated for every class in `services`.
+pub trait RemoteClass: 'static {
+    const SERVICE: &'static str;
+    const NAME: &'static str;
+}
+
+/// Handle of a remote object of class `C`.
+///
+/// The server identifies objects by id, with 0 standing for `null`. Handles of
+/// different classes are different types, so a part can't be passed where a
+/// vessel is expected.
+pub struct RemoteObject<C> {
+    id: u64,
+    class: PhantomData<fn() -> C>,
+}
+
+impl<C> RemoteObject<C> {
+    pub fn new(id: u64) -> Self {
+        RemoteObject {
+            id,
+            class: PhantomData,
+        }
+    }
+
+    /// The `null` object of class `C`.
+    pub fn null() -> Self {
+        Self::new(0)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.id == 0
+    }
+}
+
+impl<C> Clone for RemoteObject<C> {
+    fn clone(&self) -> Self {
+        Self::new(self.id)
+    }
+}
+
+impl<C> Copy for RemoteObject<C> {}
+
+impl<C> PartialEq for RemoteObject<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<C> Eq for RemoteObject<C> {}
+
+impl<C> Hash for RemoteObject<C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl<C: RemoteClass> Debug for RemoteObject<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_null() {
+            write!(f, "{}.{}(null)", C::SERVICE, C::NAME)
+        } else {
+            write!(f, "{}.{}({})", C::SERVICE, C::NAME, self.id)
+        }
+    }
+}
+
+impl<C> FromProcedureResult for RemoteObject<C> {
+    type Error = DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+        encoding::decode_uint64(&value).map(Self::new)
+    }
+}
+
+/// Results of procedures that may return `null`.
+impl<C> FromProcedureResult for Option<RemoteObject<C>> {
+    type Error = DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+        let object = RemoteObject::try_from(value)?;
+        Ok(if object.is_null() { None } else { Some(object) })
+    }
+}
+
+impl<C> EncodeArgument for RemoteObject<C> {
+    fn encode_argument(&self) -> Vec<u8> {
+        encoding::encode_uint64(self.id)
+    }
+}
+
+impl<C> EncodeArgument for Option<RemoteObject<C>> {
+    fn encode_argument(&self) -> Vec<u8> {
+        self.unwrap_or_else(RemoteObject::null).encode_argument()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    enum Vessel {}
+
+    impl RemoteClass for Vessel {
+        const SERVICE: &'static str = "SpaceCenter";
+        const NAME: &'static str = "Vessel";
+    }
+
+    #[test]
+    fn test_remote_object_roundtrip() {
+        let vessel = RemoteObject::<Vessel>::new(300);
+
+        let decoded = RemoteObject::<Vessel>::try_from(vessel.encode_argument()).unwrap();
+        assert_eq!(vessel, decoded);
+        assert_eq!("SpaceCenter.Vessel(300)", format!("{:?}", decoded));
+    }
+
+    #[test]
+    fn test_nullable_result() {
+        let null = RemoteObject::<Vessel>::null().encode_argument();
+        assert_eq!(None, Option::<RemoteObject<Vessel>>::try_from(null).unwrap());
+
+        let active = encoding::encode_uint64(7);
+        assert_eq!(
+            Some(RemoteObject::new(7)),
+            Option::<RemoteObject<Vessel>>::try_from(active).unwrap()
+        );
+        assert_eq!(vec![0], None::<RemoteObject<Vessel>>.encode_argument());
+    }
+}
@@ -0,0 +1,486 @@
+use std::fmt::{self, Debug};
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+
+use client::{Client, ClientTask, PendingCall};
+use connection::{ConnectionBuilder, ConnectionError, RpcConnection, StreamConnection,
+                 TokioConnection};
+use schema;
+use server::{ProcedureCall, ProcedureCallError, Server};
+use services::krpc;
+use stream::{removal_calls, Registration, Streams};
+
+/// How long to wait between attempts to reach the server. The delay starts at
+/// `initial` and doubles with every failed attempt, up to `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn initial(mut self, initial: Duration) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// Gives up after `max_attempts` failed attempts in a row. By default the
+    /// server is tried forever.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .checked_mul(1 << attempt.min(31))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// A change of the connection state of a `ReconnectTask`.
+#[derive(Debug)]
+pub enum Notification {
+    /// A connection was established. After a reconnect, the streams and events
+    /// of the previous connection have been added again by now.
+    Connected,
+    /// The connection was lost. Calls that were waiting for a response failed
+    /// with this error, new calls wait for the next connection.
+    Disconnected(ConnectionError),
+}
+
+type Calls = mpsc::UnboundedReceiver<PendingCall>;
+type Session = (TokioConnection<TcpStream>, StreamConnection<TcpStream>);
+
+/// Keeps a `Server` connected to a kRPC server over TCP, for example across
+/// scene changes and server restarts.
+///
+/// Every new connection repeats the RPC and stream handshakes, and the
+/// streams and events of the old one are added again. Their `TypedStream`s
+/// and `Event`s carry on, but get new ids. Streams that can't be added again,
+/// e.g. because they refer to objects of the old session, end. Dropped streams
+/// are removed on the server right away.
+pub struct ReconnectTask {
+    addr: SocketAddr,
+    builder: ConnectionBuilder,
+    backoff: Backoff,
+    streams: Streams,
+    notifications: mpsc::UnboundedSender<Notification>,
+    /// Failed connection attempts since the last successful one.
+    attempts: u32,
+    /// Requests removing dropped streams that are waiting for their response.
+    removals: Vec<Removal>,
+    state: State,
+}
+
+struct Removal {
+    ids: Vec<u64>,
+    response: oneshot::Receiver<Result<schema::Response, ConnectionError>>,
+}
+
+enum State {
+    Connecting(Box<Future<Item = Session, Error = ConnectionError> + Send>, Calls),
+    Connected(ClientTask<TokioConnection<TcpStream>>, StreamConnection<TcpStream>),
+    Waiting(Delay, Calls),
+    Finished,
+}
+
+impl ReconnectTask {
+    /// Creates a server that stays connected to `addr`, using `builder` for
+    /// every handshake.
+    ///
+    /// The returned task does the connecting and has to be spawned, it
+    /// finishes once every handle of the server is dropped. It fails when
+    /// `backoff` gives up.
+    pub fn new(
+        addr: SocketAddr,
+        builder: ConnectionBuilder,
+        backoff: Backoff,
+    ) -> (
+        Server<Client>,
+        mpsc::UnboundedReceiver<Notification>,
+        ReconnectTask,
+    ) {
+        let (calls, receiver) = mpsc::unbounded();
+        let client = Client::from_sender(calls);
+        let (notifications, notification_receiver) = mpsc::unbounded();
+
+        let streams = Streams::new();
+        let future = Box::new(connect(addr, builder.clone(), streams.clone()));
+
+        let task = ReconnectTask {
+            addr,
+            builder,
+            backoff,
+            streams: streams.clone(),
+            notifications,
+            attempts: 0,
+            removals: Vec::new(),
+            state: State::Connecting(future, receiver),
+        };
+
+        (
+            Server::with_streams(client, streams),
+            notification_receiver,
+            task,
+        )
+    }
+
+    fn notify(&self, notification: Notification) {
+        // Nobody may be listening
+        let _ = self.notifications.unbounded_send(notification);
+    }
+
+    fn wait(&self, attempt: u32, calls: Calls) -> State {
+        let delay = Delay::new(Instant::now() + self.backoff.delay(attempt));
+        State::Waiting(delay, calls)
+    }
+}
+
+impl Future for ReconnectTask {
+    type Item = ();
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<()>, ConnectionError> {
+        loop {
+            self.state = match mem::replace(&mut self.state, State::Finished) {
+                State::Connecting(mut future, calls) => match future.poll() {
+                    Ok(Async::Ready((connection, updates))) => {
+                        self.attempts = 0;
+                        self.notify(Notification::Connected);
+                        State::Connected(ClientTask::resume(connection, calls), updates)
+                    }
+                    Ok(Async::NotReady) => {
+                        self.state = State::Connecting(future, calls);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        self.attempts += 1;
+                        if let Some(max_attempts) = self.backoff.max_attempts {
+                            if self.attempts >= max_attempts {
+                                return Err(e);
+                            }
+                        }
+                        self.wait(self.attempts, calls)
+                    }
+                },
+                State::Connected(mut task, mut updates) => {
+                    let polled =
+                        poll_session(&self.streams, &mut self.removals, &mut task, &mut updates);
+                    match polled {
+                        Ok(Async::Ready(())) => return Ok(Async::Ready(())),
+                        Ok(Async::NotReady) => {
+                            self.state = State::Connected(task, updates);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => {
+                            let calls = task.into_calls(&e);
+                            self.removals.clear();
+                            self.streams.detach();
+                            self.notify(Notification::Disconnected(e));
+                            self.wait(0, calls)
+                        }
+                    }
+                }
+                State::Waiting(mut delay, calls) => match delay.poll() {
+                    Ok(Async::Ready(())) => {
+                        let future = connect(self.addr, self.builder.clone(), self.streams.clone());
+                        State::Connecting(Box::new(future), calls)
+                    }
+                    Ok(Async::NotReady) => {
+                        self.state = State::Waiting(delay, calls);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+                State::Finished => panic!("ReconnectTask polled after it finished"),
+            };
+        }
+    }
+}
+
+impl Debug for ReconnectTask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let state = match self.state {
+            State::Connecting(..) => "Connecting",
+            State::Connected(..) => "Connected",
+            State::Waiting(..) => "Waiting",
+            State::Finished => "Finished",
+        };
+
+        f.debug_struct("ReconnectTask")
+            .field("addr", &self.addr)
+            .field("state", &state)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+/// Drives the calls and stream updates of one connection.
+fn poll_session(
+    streams: &Streams,
+    removals: &mut Vec<Removal>,
+    task: &mut ClientTask<TokioConnection<TcpStream>>,
+    updates: &mut StreamConnection<TcpStream>,
+) -> Result<Async<()>, ConnectionError> {
+    loop {
+        match updates.poll()? {
+            Async::Ready(Some(update)) => streams.deliver(update),
+            Async::Ready(None) => return Err(ConnectionError::Closed),
+            Async::NotReady => break,
+        }
+    }
+
+    if let Async::Ready(ids) = streams.poll_removed() {
+        let request = schema::Request {
+            calls: removal_calls(&ids),
+        };
+        let response = task.enqueue(request);
+        removals.push(Removal { ids, response });
+    }
+
+    let polled = task.poll();
+
+    let mut i = 0;
+    while i < removals.len() {
+        let response = match removals[i].response.poll() {
+            Ok(Async::Ready(response)) => response.ok(),
+            Ok(Async::NotReady) => {
+                i += 1;
+                continue;
+            }
+            Err(oneshot::Canceled) => None,
+        };
+
+        let removal = removals.swap_remove(i);
+        match response {
+            Some(response) => streams.removal_answered(removal.ids, &response),
+            None => streams.removal_failed(removal.ids),
+        }
+    }
+
+    polled
+}
+
+#[async]
+fn connect(
+    addr: SocketAddr,
+    builder: ConnectionBuilder,
+    streams: Streams,
+) -> Result<Session, ConnectionError> {
+    let tcp = await!(TcpStream::connect(&addr))?;
+    let connection = await!(builder.clone().initialize(tcp))?;
+    let client_identifier = connection.client_identifier().to_vec();
+
+    let tcp = await!(TcpStream::connect(&addr))?;
+    let updates = await!(builder.initialize_stream(tcp, client_identifier))?;
+
+    let server = await!(restore(Server::with_streams(connection, streams)))?;
+    Ok((server.into_inner(), updates))
+}
+
+/// Adds the detached streams and events of `server` again.
+#[async]
+fn restore<C: RpcConnection>(server: Server<C>) -> Result<Server<C>, ConnectionError> {
+    let mut server = server;
+
+    for (key, registration) in server.streams().detached() {
+        let added = match registration {
+            Registration::Stream(call) => {
+                match await!(server.invoke(krpc::AddStream { call, start: true })) {
+                    Ok((stream, s)) => {
+                        s.streams().attach(key, stream.id);
+                        Ok(s)
+                    }
+                    Err(e) => Err(e.cast::<krpc::AddEvent>()),
+                }
+            }
+            Registration::Event(expression) => match await!(server.create_event(expression)) {
+                Ok((id, s)) => {
+                    s.streams().attach(key, id);
+                    await!(s.invoke(krpc::StartStream { id }))
+                        .map(|(_, s)| s)
+                        .map_err(ProcedureCallError::cast::<krpc::AddEvent>)
+                }
+                Err(e) => Err(e),
+            },
+        };
+
+        server = match added {
+            Ok(s) => s,
+            Err(e) => {
+                let s = into_server(e)?;
+                s.streams().remove(key);
+                s
+            }
+        };
+    }
+
+    Ok(server)
+}
+
+/// The server of a failed call, unless the connection itself failed.
+fn into_server<P, C>(e: ProcedureCallError<P, C>) -> Result<Server<C>, ConnectionError>
+where
+    P: ProcedureCall,
+{
+    match e {
+        ProcedureCallError::Connection(e) => Err(e),
+        ProcedureCallError::TimedOut(s)
+        | ProcedureCallError::Procedure(_, s)
+        | ProcedureCallError::NoResult(s)
+        | ProcedureCallError::Request(_, s)
+        | ProcedureCallError::Decode(_, s) => Ok(s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prost::Message;
+
+    use encoding;
+    use expression::Expression;
+    use schema;
+    use stream::Event;
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff::new()
+            .initial(Duration::from_millis(100))
+            .max(Duration::from_secs(1));
+
+        assert_eq!(Duration::from_millis(100), backoff.delay(0));
+        assert_eq!(Duration::from_millis(200), backoff.delay(1));
+        assert_eq!(Duration::from_millis(800), backoff.delay(3));
+        assert_eq!(Duration::from_secs(1), backoff.delay(4));
+        assert_eq!(Duration::from_secs(1), backoff.delay(1000));
+    }
+
+    /// Adds streams with ids counting up from 100, fails to add a stream of
+    /// `GetServices` and hands out object ids for expressions.
+    #[derive(Debug, Default)]
+    struct MockConnection {
+        next_id: u64,
+        procedures: Vec<String>,
+    }
+
+    impl RpcConnection for MockConnection {
+        fn call(
+            mut self,
+            r: schema::Request,
+        ) -> Box<Future<Item = (schema::Response, Self), Error = ConnectionError> + Send> {
+            let mut results = Vec::new();
+            for call in r.calls {
+                self.next_id += 1;
+
+                let mut result = schema::ProcedureResult {
+                    error: None,
+                    value: Vec::new(),
+                };
+                match call.procedure.as_str() {
+                    "AddStream" => {
+                        let streamed = schema::ProcedureCall::decode(&call.arguments[0].value);
+                        if streamed.unwrap().procedure == "GetServices" {
+                            result.error = Some(schema::Error {
+                                service: "KRPC".to_string(),
+                                name: "InvalidOperationException".to_string(),
+                                description: "Not streamable".to_string(),
+                                stack_trace: String::new(),
+                            });
+                        } else {
+                            result.value = encoding::encode_message(&schema::Stream {
+                                id: 100 + self.next_id,
+                            });
+                        }
+                    }
+                    "AddEvent" => {
+                        result.value = encoding::encode_message(&schema::Event {
+                            stream: Some(schema::Stream {
+                                id: 100 + self.next_id,
+                            }),
+                        })
+                    }
+                    "StartStream" => {}
+                    _ => result.value = encoding::encode_uint64(self.next_id),
+                }
+                results.push(result);
+                self.procedures.push(call.procedure);
+            }
+
+            Box::new(::futures::future::ok((
+                schema::Response {
+                    error: None,
+                    results,
+                },
+                self,
+            )))
+        }
+    }
+
+    #[test]
+    fn test_restore_streams() {
+        let streams = Streams::new();
+        let stream = streams.register::<schema::Status>(
+            1,
+            Registration::Stream(krpc::GetStatus.into()),
+        );
+        let failing = streams.register::<schema::Services>(
+            2,
+            Registration::Stream(encoding::procedure_call("KRPC", "GetServices", Vec::new())),
+        );
+        let event = Event::new(streams.subscribe(
+            3,
+            Registration::Event(Expression::call(krpc::GetStatus).equal(true)),
+        ));
+        streams.detach();
+
+        let server = Server::with_streams(MockConnection::default(), streams.clone());
+        let server = restore(server).wait().unwrap();
+
+        assert!(streams.detached().is_empty());
+        assert_eq!(101, stream.id());
+        assert!(failing.wait().next().is_none());
+        assert_eq!(106, event.id());
+
+        assert_eq!(
+            vec![
+                "AddStream",
+                "AddStream",
+                "Expression_static_Call",
+                "Expression_static_ConstantBool",
+                "Expression_static_Equal",
+                "AddEvent",
+                "StartStream",
+            ],
+            server.into_inner().procedures
+        );
+    }
+}
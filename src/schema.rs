@@ -102,12 +102,12 @@ pub struct StreamResult {
 }
 // Messages for receiving information about the server
 
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Services {
     #[prost(message, repeated, tag = "1")]
     pub services: ::std::vec::Vec<Service>,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Service {
     #[prost(string, tag = "1")]
     pub name: String,
@@ -122,7 +122,7 @@ pub struct Service {
     #[prost(string, tag = "6")]
     pub documentation: String,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Procedure {
     #[prost(string, tag = "1")]
     pub name: String,
@@ -135,7 +135,7 @@ pub struct Procedure {
     #[prost(string, tag = "5")]
     pub documentation: String,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Parameter {
     #[prost(string, tag = "1")]
     pub name: String,
@@ -144,14 +144,14 @@ pub struct Parameter {
     #[prost(bytes, tag = "3")]
     pub default_value: Vec<u8>,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Class {
     #[prost(string, tag = "1")]
     pub name: String,
     #[prost(string, tag = "2")]
     pub documentation: String,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Enumeration {
     #[prost(string, tag = "1")]
     pub name: String,
@@ -160,7 +160,7 @@ pub struct Enumeration {
     #[prost(string, tag = "3")]
     pub documentation: String,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct EnumerationValue {
     #[prost(string, tag = "1")]
     pub name: String,
@@ -169,14 +169,14 @@ pub struct EnumerationValue {
     #[prost(string, tag = "3")]
     pub documentation: String,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Exception {
     #[prost(string, tag = "1")]
     pub name: String,
     #[prost(string, tag = "2")]
     pub documentation: String,
 }
-#[derive(Clone, PartialEq, Message, Serialize)]
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
 pub struct Type {
     #[prost(enumeration = "type_::TypeCode", tag = "1")]
     pub code: i32,
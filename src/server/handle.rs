@@ -0,0 +1,1002 @@
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::prelude::*;
+
+use super::{FromProcedureResult, ProcedureCall};
+use connection::{ConnectionError, RpcConnection};
+use discovery::{ProcedureIds, ServiceTree};
+use expression::{levels, Expression, ExpressionCall, RemoteHandle};
+use object::RemoteObject;
+use schema;
+use services::krpc;
+use stream::{Event, Registration, Streams, TypedStream};
+use value::{DynamicCall, InvalidCall, Value};
+
+#[derive(Debug, Clone)]
+pub struct Server<C> {
+    connection: C,
+    streams: Streams,
+    timeout: Option<Duration>,
+    /// The services used by `invoke_dynamic`, fetched on its first call.
+    service_tree: Option<Arc<ServiceTree>>,
+    procedure_ids: Option<Arc<ProcedureIds>>,
+}
+
+impl<C> Server<C> {
+    pub fn new(connection: C) -> Self {
+        Self::with_streams(connection, Streams::new())
+    }
+
+    /// Creates a server whose streams are routed through `streams`, which is
+    /// shared with other servers.
+    pub(crate) fn with_streams(connection: C, streams: Streams) -> Self {
+        Server {
+            connection,
+            streams,
+            timeout: None,
+            service_tree: None,
+            procedure_ids: None,
+        }
+    }
+
+    /// The time to wait for the response to a call before failing it with
+    /// `ProcedureCallError::TimedOut`, unless the call has its own timeout.
+    /// Connections that can't skip the late response fail with
+    /// `ConnectionError::TimedOut` instead. Timeouts need a tokio runtime.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends calls with the ids of their procedures in `services` instead of
+    /// their names, which makes them a lot smaller. Procedures that are not
+    /// in `services` are still called by name. `invoke_dynamic` does this
+    /// with the services it fetches, unless this was called before.
+    pub fn resolve_calls(mut self, services: &ServiceTree) -> Self {
+        self.procedure_ids = Some(Arc::new(ProcedureIds::new(services)));
+        self
+    }
+
+    pub fn into_inner(self) -> C {
+        self.connection
+    }
+
+    /// The streams added through this server. Their updates only arrive once
+    /// `Streams::dispatch` is running on the matching `StreamConnection`.
+    pub fn streams(&self) -> &Streams {
+        &self.streams
+    }
+
+    /// A request for `calls`, after the removal of dropped streams. Returns
+    /// the ids of the removed streams, whose results come first.
+    fn request(&self, calls: Vec<schema::ProcedureCall>) -> (schema::Request, Vec<u64>) {
+        let (mut request, removed) = self.streams.request(calls);
+        if let Some(ref ids) = self.procedure_ids {
+            ids.resolve_request(&mut request);
+        }
+        (request, removed)
+    }
+}
+
+impl<C: RpcConnection> Server<C> {
+    pub fn invoke<P: ProcedureCall>(
+        self,
+        p: P,
+    ) -> impl Future<Item = (P::Result, Self), Error = ProcedureCallError<P, C>> {
+        let timeout = self.timeout;
+        self.invoke_within(p, timeout)
+    }
+
+    /// Like `invoke`, but with a timeout for just this call.
+    pub fn invoke_timeout<P: ProcedureCall>(
+        self,
+        p: P,
+        timeout: Duration,
+    ) -> impl Future<Item = (P::Result, Self), Error = ProcedureCallError<P, C>> {
+        self.invoke_within(p, Some(timeout))
+    }
+
+    #[async]
+    fn invoke_within<P: ProcedureCall>(
+        self,
+        p: P,
+        timeout: Option<Duration>,
+    ) -> Result<(P::Result, Self), ProcedureCallError<P, C>> {
+        let (request, removed) = self.request(vec![p.into()]);
+        let skip = removed.len();
+        let Server {
+            connection,
+            streams,
+            timeout: default_timeout,
+            service_tree,
+            procedure_ids,
+        } = self;
+
+        let (response, c): (Option<schema::Response>, C) =
+            await!(send(connection, request, timeout, &streams, removed))?;
+        let server = Server {
+            connection: c,
+            streams,
+            timeout: default_timeout,
+            service_tree,
+            procedure_ids,
+        };
+
+        let response = match response {
+            Some(response) => response,
+            None => return Err(ProcedureCallError::TimedOut(server)),
+        };
+        if let Some(e) = response.error {
+            return Err(ProcedureCallError::Request(e, server));
+        }
+
+        let result = match response.results.into_iter().nth(skip) {
+            Some(result) => result,
+            None => return Err(ProcedureCallError::NoResult(server)),
+        };
+
+        if let Some(e) = result.error {
+            return Err(ProcedureCallError::Procedure(e.into(), server));
+        }
+
+        let result = result.value;
+
+        use self::FromProcedureResult;
+        let result = match P::Result::try_from(result) {
+            Ok(v) => v,
+            Err(e) => return Err(ProcedureCallError::Decode(e, server)),
+        };
+
+        Ok((result, server))
+    }
+
+    /// Sends all calls of `batch` in one request. Each call gets its own
+    /// result, so a failing call doesn't affect the others.
+    #[async]
+    pub fn invoke_batch<B: Batch>(self, batch: B) -> Result<(B::Results, Self), BatchError<C>> {
+        let (request, removed) = self.request(batch.into_calls());
+        let skip = removed.len();
+        let Server {
+            connection,
+            streams,
+            timeout,
+            service_tree,
+            procedure_ids,
+        } = self;
+
+        let expected = request.calls.len();
+
+        let (response, c): (Option<schema::Response>, C) =
+            await!(send(connection, request, timeout, &streams, removed))?;
+        let server = Server {
+            connection: c,
+            streams,
+            timeout,
+            service_tree,
+            procedure_ids,
+        };
+
+        let response = match response {
+            Some(response) => response,
+            None => return Err(BatchError::TimedOut(server)),
+        };
+        if let Some(e) = response.error {
+            return Err(BatchError::Request(e, server));
+        }
+
+        if response.results.len() != expected {
+            return Err(BatchError::MissingResults(server));
+        }
+
+        let results = response.results.into_iter().skip(skip).collect();
+
+        Ok((B::from_results(results), server))
+    }
+
+    /// Fetches the description of all services on the server.
+    pub fn services(
+        self,
+    ) -> impl Future<Item = (ServiceTree, Self), Error = ProcedureCallError<krpc::GetServices, C>>
+    {
+        self.invoke(krpc::GetServices)
+            .map(|(services, server)| (ServiceTree::new(services), server))
+    }
+
+    /// Calls `service.procedure` with arguments checked against the services
+    /// of the server, which are fetched on the first dynamic call. From then
+    /// on calls are sent by id, see `resolve_calls`. Calls that don't match
+    /// the services fail with `DynamicCallError::Invalid` unsent.
+    pub fn invoke_dynamic(
+        self,
+        service: &str,
+        procedure: &str,
+        arguments: Vec<Value>,
+    ) -> impl Future<Item = (Value, Self), Error = DynamicCallError<C>> {
+        self.invoke_dynamic_owned(service.to_string(), procedure.to_string(), arguments)
+    }
+
+    #[async]
+    fn invoke_dynamic_owned(
+        self,
+        service: String,
+        procedure: String,
+        arguments: Vec<Value>,
+    ) -> Result<(Value, Self), DynamicCallError<C>> {
+        let (service_tree, mut server) = match self.service_tree.clone() {
+            Some(service_tree) => (service_tree, self),
+            None => {
+                let (service_tree, mut server) = await!(self.services())
+                    .map_err(ProcedureCallError::cast::<DynamicCall>)?;
+                if server.procedure_ids.is_none() {
+                    server = server.resolve_calls(&service_tree);
+                }
+                (Arc::new(service_tree), server)
+            }
+        };
+        server.service_tree = Some(service_tree.clone());
+
+        let call = match DynamicCall::new(&service_tree, &service, &procedure, &arguments) {
+            Ok(call) => call,
+            Err(e) => return Err(DynamicCallError::Invalid(e, server)),
+        };
+        let return_type = call.return_type().clone();
+
+        let (result, server) = await!(server.invoke(call))?;
+        match Value::decode(&return_type, &result.0) {
+            Ok(value) => Ok((value, server)),
+            Err(e) => Err(ProcedureCallError::Decode(e, server).into()),
+        }
+    }
+
+    /// Registers `p` as a stream on the server and starts it.
+    #[async]
+    pub fn add_stream<P: ProcedureCall>(
+        self,
+        p: P,
+    ) -> Result<(TypedStream<P::Result>, Self), ProcedureCallError<krpc::AddStream, C>> {
+        let call: schema::ProcedureCall = p.into();
+        let add = krpc::AddStream {
+            call: call.clone(),
+            start: true,
+        };
+
+        let (stream, server) = await!(self.invoke(add))?;
+        let stream = server
+            .streams
+            .register(stream.id, Registration::Stream(call));
+
+        Ok((stream, server))
+    }
+
+    /// Sends `expression` to the server and registers an event for it. The
+    /// returned `Event` resolves once the expression evaluates to true.
+    #[async]
+    pub fn add_event(
+        self,
+        expression: Expression,
+    ) -> Result<(Event, Self), ProcedureCallError<krpc::AddEvent, C>> {
+        let (id, server) = await!(self.create_event(expression.clone()))?;
+
+        // Subscribe before starting, so the first result can't be missed
+        let subscription = server
+            .streams
+            .subscribe(id, Registration::Event(expression));
+        let (_, server) = await!(server.invoke(krpc::StartStream { id }))
+            .map_err(ProcedureCallError::cast::<krpc::AddEvent>)?;
+
+        Ok((Event::new(subscription), server))
+    }
+
+    /// Builds `expression` on the server and adds an event for it without
+    /// starting its stream. Returns the stream id of the event.
+    ///
+    /// The nodes of the expression are created with one batch per level of
+    /// the tree.
+    #[async]
+    pub(crate) fn create_event(
+        self,
+        expression: Expression,
+    ) -> Result<(u64, Self), ProcedureCallError<krpc::AddEvent, C>> {
+        let mut server = self;
+
+        let instructions = expression.compile();
+        // Filled in level by level, before any instruction refers to them
+        let mut handles = vec![RemoteHandle(0); instructions.len()];
+        for level in levels(&instructions) {
+            let calls: Vec<ExpressionCall> = level
+                .iter()
+                .map(|&i| instructions[i].to_call(&handles))
+                .collect();
+
+            let (results, s) = match await!(server.invoke_batch(calls)) {
+                Ok(ok) => ok,
+                Err(BatchError::Connection(e)) => return Err(ProcedureCallError::Connection(e)),
+                Err(BatchError::TimedOut(s)) => return Err(ProcedureCallError::TimedOut(s)),
+                Err(BatchError::MissingResults(s)) => return Err(ProcedureCallError::NoResult(s)),
+                Err(BatchError::Request(e, s)) => return Err(ProcedureCallError::Request(e, s)),
+            };
+            server = s;
+
+            for (i, result) in level.into_iter().zip(results) {
+                match result {
+                    Ok(handle) => handles[i] = handle,
+                    Err(e) => return Err(ProcedureCallError::Procedure(e, server)),
+                }
+            }
+        }
+        let root = handles.pop().expect("an expression has at least one node");
+
+        let (event, server) = await!(server.invoke(krpc::AddEvent {
+            expression: RemoteObject::new(root.0),
+        }))?;
+        match event.stream {
+            Some(stream) => Ok((stream.id, server)),
+            None => Err(ProcedureCallError::NoResult(server)),
+        }
+    }
+}
+
+/// Sends `request`, giving up on it after `timeout` if there is one. The
+/// streams `removed` by it are forgotten once it is answered.
+fn send<C: RpcConnection>(
+    connection: C,
+    request: schema::Request,
+    timeout: Option<Duration>,
+    streams: &Streams,
+    removed: Vec<u64>,
+) -> Box<Future<Item = (Option<schema::Response>, C), Error = ConnectionError> + Send> {
+    let call: Box<Future<Item = (Option<schema::Response>, C), Error = ConnectionError> + Send> =
+        match timeout {
+            Some(timeout) => connection.call_timeout(request, timeout),
+            None => Box::new(
+                connection
+                    .call(request)
+                    .map(|(response, connection)| (Some(response), connection)),
+            ),
+        };
+
+    let streams = streams.clone();
+    Box::new(call.then(move |result| {
+        match result {
+            Ok((Some(ref response), _)) => streams.removal_answered(removed, response),
+            Ok((None, _)) | Err(_) => streams.removal_failed(removed),
+        }
+        result
+    }))
+}
+
+#[derive(Debug)]
+pub enum ProcedureCallError<P: ProcedureCall, C> {
+    Connection(ConnectionError),
+    /// There was no response within the timeout of the call. The response is
+    /// skipped once it arrives, so the server can still be used.
+    TimedOut(Server<C>),
+    Procedure(P::Error, Server<C>),
+    NoResult(Server<C>),
+    Request(schema::Error, Server<C>),
+    Decode(<P::Result as FromProcedureResult>::Error, Server<C>),
+}
+
+impl<P: ProcedureCall, C> ProcedureCallError<P, C> {
+    /// Converts the error of one call into that of another call with
+    /// compatible error types.
+    pub(crate) fn cast<Q>(self) -> ProcedureCallError<Q, C>
+    where
+        Q: ProcedureCall,
+        Q::Error: From<P::Error>,
+        Q::Result: FromProcedureResult<Error = <P::Result as FromProcedureResult>::Error>,
+    {
+        match self {
+            ProcedureCallError::Connection(e) => ProcedureCallError::Connection(e),
+            ProcedureCallError::TimedOut(s) => ProcedureCallError::TimedOut(s),
+            ProcedureCallError::Procedure(e, s) => ProcedureCallError::Procedure(e.into(), s),
+            ProcedureCallError::NoResult(s) => ProcedureCallError::NoResult(s),
+            ProcedureCallError::Request(e, s) => ProcedureCallError::Request(e, s),
+            ProcedureCallError::Decode(e, s) => ProcedureCallError::Decode(e, s),
+        }
+    }
+}
+
+impl<P: ProcedureCall, C> ::std::fmt::Display for ProcedureCallError<P, C> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        match *self {
+            ProcedureCallError::Connection(ref e) => write!(f, "Connection Error: {}", e),
+            ProcedureCallError::TimedOut(_) => write!(f, "No response within the timeout"),
+            ProcedureCallError::Procedure(ref e, _) => write!(f, "Procedure Error: {}", e),
+            ProcedureCallError::NoResult(_) => write!(f, "No result for procedure call"),
+            ProcedureCallError::Request(ref e, _) => write!(f, "Request Error: {}", e),
+            ProcedureCallError::Decode(ref e, _) => write!(f, "Decode Error: {:?}", e),
+        }
+    }
+}
+
+impl<P, C> ::failure::Fail for ProcedureCallError<P, C>
+where
+    P: ProcedureCall + Debug,
+    P::Result: Debug,
+    <P::Result as FromProcedureResult>::Error: Debug + Send + Sync,
+    C: Debug + Send + Sync + 'static,
+{
+    fn cause(&self) -> Option<&::failure::Fail> {
+        match *self {
+            ProcedureCallError::Connection(ref e) => Some(e),
+            ProcedureCallError::Procedure(ref e, _) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<P, C> From<ConnectionError> for ProcedureCallError<P, C>
+where
+    P: ProcedureCall,
+{
+    fn from(e: ConnectionError) -> Self {
+        ProcedureCallError::Connection(e)
+    }
+}
+
+/// The error of `Server::invoke_dynamic`.
+#[derive(Debug)]
+pub enum DynamicCallError<C> {
+    /// The call doesn't match the services of the server and wasn't sent.
+    Invalid(InvalidCall, Server<C>),
+    Call(ProcedureCallError<DynamicCall, C>),
+}
+
+impl<C> Display for DynamicCallError<C> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        match *self {
+            DynamicCallError::Invalid(ref e, _) => write!(f, "Invalid Call: {}", e),
+            DynamicCallError::Call(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<C> ::failure::Fail for DynamicCallError<C>
+where
+    C: Debug + Send + Sync + 'static,
+{
+    fn cause(&self) -> Option<&::failure::Fail> {
+        match *self {
+            DynamicCallError::Invalid(ref e, _) => Some(e),
+            DynamicCallError::Call(ref e) => e.cause(),
+        }
+    }
+}
+
+impl<C> From<ProcedureCallError<DynamicCall, C>> for DynamicCallError<C> {
+    fn from(e: ProcedureCallError<DynamicCall, C>) -> Self {
+        DynamicCallError::Call(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum BatchError<C> {
+    Connection(ConnectionError),
+    /// See `ProcedureCallError::TimedOut`.
+    TimedOut(Server<C>),
+    MissingResults(Server<C>),
+    Request(schema::Error, Server<C>),
+}
+
+impl<C> Display for BatchError<C> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        match *self {
+            BatchError::Connection(ref e) => write!(f, "Connection Error: {}", e),
+            BatchError::TimedOut(_) => write!(f, "No response within the timeout"),
+            BatchError::MissingResults(_) => write!(f, "Not every call of the batch has a result"),
+            BatchError::Request(ref e, _) => write!(f, "Request Error: {}", e),
+        }
+    }
+}
+
+impl<C> ::failure::Fail for BatchError<C>
+where
+    C: Debug + Send + Sync + 'static,
+{
+    fn cause(&self) -> Option<&::failure::Fail> {
+        match *self {
+            BatchError::Connection(ref e) => Some(e),
+            BatchError::Request(ref e, _) => Some(e),
+            BatchError::TimedOut(_) | BatchError::MissingResults(_) => None,
+        }
+    }
+}
+
+impl<C> From<ConnectionError> for BatchError<C> {
+    fn from(e: ConnectionError) -> Self {
+        BatchError::Connection(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::run_test;
+
+    use proptest::prelude::*;
+
+    use connection::RpcConnection;
+    use server::SimpleResultError;
+
+    static MOCK_SERVICE: &str = "mock-service";
+    static MOCK_PROCEDURE: &str = "mock-procedure";
+
+    #[test]
+    fn test_server_into_inner() {
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        struct MockConnection(u32);
+
+        let connection = MockConnection(42);
+
+        let server = Server::new(connection);
+
+        let unwrapped_connection = server.into_inner();
+
+        assert_eq!(connection, unwrapped_connection);
+    }
+
+    #[test]
+    fn test_timeout() {
+        use std::time::Duration;
+        use tokio::runtime::current_thread::Runtime;
+
+        // Never answers
+        #[derive(Debug)]
+        struct MockConnection;
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                _: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                Box::new(::futures::future::empty())
+            }
+        }
+
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::new(MockConnection).timeout(Duration::from_millis(10));
+        match runtime.block_on(server.invoke(krpc::GetStatus)) {
+            Err(ProcedureCallError::Connection(ConnectionError::TimedOut)) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|(status, _)| status)),
+        }
+
+        // Never answers, but can skip the late response
+        #[derive(Debug)]
+        struct SkippingConnection;
+        impl RpcConnection for SkippingConnection {
+            fn call(
+                self,
+                _: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                Box::new(::futures::future::empty())
+            }
+
+            fn call_timeout(
+                self,
+                _: ::schema::Request,
+                _: Duration,
+            ) -> Box<
+                Future<Item = (Option<::schema::Response>, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                Box::new(::futures::future::ok((None, self)))
+            }
+        }
+
+        let server = Server::new(SkippingConnection).timeout(Duration::from_millis(10));
+        let server = match runtime.block_on(server.invoke(krpc::GetStatus)) {
+            Err(ProcedureCallError::TimedOut(server)) => server,
+            other => panic!("Unexpected result: {:?}", other.map(|(status, _)| status)),
+        };
+        match runtime.block_on(server.invoke_batch(vec![krpc::GetStatus])) {
+            Err(BatchError::TimedOut(_)) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|(results, _)| results)),
+        }
+
+        let server = Server::new(MockConnection);
+        let call = server.invoke_timeout(krpc::GetStatus, Duration::from_millis(10));
+        match runtime.block_on(call) {
+            Err(ProcedureCallError::Connection(ConnectionError::TimedOut)) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|(status, _)| status)),
+        }
+    }
+
+    #[test]
+    fn test_services() {
+        #[derive(Debug)]
+        struct MockConnection;
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                r: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                assert_eq!("GetServices", extract_krpc_call(r).procedure);
+
+                let services = ::schema::Services {
+                    services: vec![::schema::Service {
+                        name: "KRPC".to_string(),
+                        ..Default::default()
+                    }],
+                };
+                let response = ::schema::Response {
+                    error: None,
+                    results: vec![::schema::ProcedureResult {
+                        error: None,
+                        value: ::encoding::encode_message(&services),
+                    }],
+                };
+                Box::new(::futures::future::ok((response, self)))
+            }
+        }
+
+        let (services, _) = Server::new(MockConnection).services().wait().unwrap();
+        assert!(services.service("KRPC").is_some());
+        assert!(services.service("SpaceCenter").is_none());
+    }
+
+    #[test]
+    fn test_resolve_calls() {
+        // Answers calls of KRPC.GetStatus by id
+        #[derive(Debug)]
+        struct MockConnection;
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                mut r: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                let call = r.calls.pop().unwrap();
+                assert_eq!(("", ""), (&call.service[..], &call.procedure[..]));
+                assert_eq!((1, 2), (call.service_id, call.procedure_id));
+
+                let response = ::schema::Response {
+                    error: None,
+                    results: vec![::schema::ProcedureResult {
+                        error: None,
+                        value: ::encoding::encode_message(&::schema::Status::default()),
+                    }],
+                };
+                Box::new(::futures::future::ok((response, self)))
+            }
+        }
+
+        let procedure = |name: &str| ::schema::Procedure {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let services = ServiceTree::new(::schema::Services {
+            services: vec![::schema::Service {
+                name: "KRPC".to_string(),
+                procedures: vec![procedure("GetClientID"), procedure("GetStatus")],
+                ..Default::default()
+            }],
+        });
+
+        let server = Server::new(MockConnection).resolve_calls(&services);
+        server.invoke(krpc::GetStatus).wait().unwrap();
+    }
+
+    #[test]
+    fn test_invoke_dynamic() {
+        use schema::type_::TypeCode;
+        use value::InvalidCall;
+
+        // Counts the times the services were fetched
+        #[derive(Debug)]
+        struct MockConnection(u32);
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                r: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                let call = r.calls.into_iter().next().unwrap();
+                let (value, fetched) = if call.procedure == "GetServices" {
+                    let string = ::schema::Type {
+                        code: TypeCode::String as i32,
+                        ..Default::default()
+                    };
+                    let services = ::schema::Services {
+                        services: vec![::schema::Service {
+                            name: "SpaceCenter".to_string(),
+                            procedures: vec![::schema::Procedure {
+                                name: "Vessel_get_Name".to_string(),
+                                parameters: vec![::schema::Parameter {
+                                    name: "this".to_string(),
+                                    type_: Some(::schema::Type {
+                                        code: TypeCode::Class as i32,
+                                        service: "SpaceCenter".to_string(),
+                                        name: "Vessel".to_string(),
+                                        types: Vec::new(),
+                                    }),
+                                    default_value: Vec::new(),
+                                }],
+                                return_type: Some(string),
+                                ..Default::default()
+                            }],
+                            ..Default::default()
+                        }],
+                    };
+                    (::encoding::encode_message(&services), self.0 + 1)
+                } else {
+                    // Sent by id once the services are known
+                    assert_eq!((1, 1), (call.service_id, call.procedure_id));
+                    assert_eq!(::encoding::encode_uint64(7), call.arguments[0].value);
+                    (::encoding::encode_string("Kerbal X"), self.0)
+                };
+
+                let response = ::schema::Response {
+                    error: None,
+                    results: vec![::schema::ProcedureResult { error: None, value }],
+                };
+                Box::new(::futures::future::ok((response, MockConnection(fetched))))
+            }
+        }
+
+        let server = Server::new(MockConnection(0));
+        let (name, server) = server
+            .invoke_dynamic("SpaceCenter", "Vessel_get_Name", vec![Value::Class(7)])
+            .wait()
+            .unwrap();
+        assert_eq!(Value::String("Kerbal X".to_string()), name);
+
+        let call = server.invoke_dynamic("SpaceCenter", "Vessel_get_Name", vec![Value::Uint64(7)]);
+        let server = match call.wait() {
+            Err(DynamicCallError::Invalid(InvalidCall::InvalidArgument(..), server)) => server,
+            other => panic!("Unexpected result: {:?}", other.map(|(value, _)| value)),
+        };
+        assert_eq!(1, server.into_inner().0);
+    }
+
+    #[test]
+    fn test_echo() {
+        run_test(
+            &any::<u32>(),
+            |v| {
+                #[derive(Debug)]
+                struct MockConnection;
+                impl RpcConnection for MockConnection {
+                    fn call(
+                        self,
+                        r: ::schema::Request,
+                    ) -> Box<
+                        Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                            + ::std::marker::Send,
+                    > {
+                        let mut call = extract_call(r);
+                        assert_eq!(1, call.arguments.len());
+
+                        let argument = call.arguments.pop().unwrap();
+                        assert_eq!(0, argument.position);
+
+                        let arg = ::encoding::decode_uint32(&argument.value).unwrap();
+                        let encoded = arg.encode_argument();
+
+                        Box::new(::futures::future::ok((
+                            ::schema::Response {
+                                error: None,
+                                results: vec![::schema::ProcedureResult {
+                                    error: None,
+                                    value: encoded,
+                                }],
+                            },
+                            self,
+                        )))
+                    }
+                }
+
+                #[derive(Debug, Eq, PartialEq)]
+                struct MockRequest {
+                    data: u32,
+                }
+
+                impl ProcedureCall for MockRequest {
+                    type Result = u32;
+                    type Error = SimpleResultError;
+                }
+
+                impl From<MockRequest> for ::schema::ProcedureCall {
+                    fn from(r: MockRequest) -> Self {
+                        ::schema::ProcedureCall {
+                            service: String::from(MOCK_SERVICE),
+                            procedure: String::from(MOCK_PROCEDURE),
+                            arguments: vec![r.data.to_argument(0)],
+                            ..Default::default()
+                        }
+                    }
+                }
+
+                let request = MockRequest { data: *v };
+
+                let server = Server::new(MockConnection);
+
+                let (result, _) = server.invoke(request).wait().unwrap();
+
+                prop_assert_eq!(*v, result);
+
+                Ok(())
+            },
+            file!(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_dropped_stream_is_removed() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Debug)]
+        struct MockConnection(Arc<Mutex<Vec<Vec<String>>>>);
+        impl RpcConnection for MockConnection {
+            fn call(
+                self,
+                r: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                let procedures = r.calls.iter().map(|c| c.procedure.clone()).collect();
+                self.0.lock().unwrap().push(procedures);
+
+                let results = r.calls
+                    .iter()
+                    .map(|c| {
+                        let value = match c.procedure.as_str() {
+                            "AddStream" => {
+                                ::encoding::encode_message(&::schema::Stream { id: 9 })
+                            }
+                            _ => Vec::new(),
+                        };
+                        ::schema::ProcedureResult { error: None, value }
+                    })
+                    .collect();
+
+                Box::new(::futures::future::ok((
+                    ::schema::Response {
+                        error: None,
+                        results,
+                    },
+                    self,
+                )))
+            }
+        }
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::new(MockConnection(requests.clone()));
+
+        let (stream, server) = server.add_stream(krpc::GetStatus).wait().unwrap();
+        assert_eq!(9, stream.id());
+
+        drop(stream);
+        let (_, server) = server.invoke(krpc::GetStatus).wait().unwrap();
+        server.invoke(krpc::GetStatus).wait().unwrap();
+
+        assert_eq!(
+            vec![
+                vec!["AddStream".to_string()],
+                vec!["RemoveStream".to_string(), "GetStatus".to_string()],
+                vec!["GetStatus".to_string()],
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_add_event() {
+        use expression::Expression;
+
+        // Hands out consecutive object ids and records the requests it receives
+        #[derive(Debug)]
+        struct MockConnection(u64, Vec<Vec<String>>);
+        impl RpcConnection for MockConnection {
+            fn call(
+                mut self,
+                r: ::schema::Request,
+            ) -> Box<
+                Future<Item = (::schema::Response, Self), Error = ConnectionError>
+                    + ::std::marker::Send,
+            > {
+                let mut results = Vec::new();
+                let mut procedures = Vec::new();
+                for call in r.calls {
+                    assert_eq!("KRPC", call.service);
+                    let value = match call.procedure.as_str() {
+                        "AddEvent" => {
+                            assert_eq!(vec![3], call.arguments[0].value);
+                            ::encoding::encode_message(&::schema::Event {
+                                stream: Some(::schema::Stream { id: 4 }),
+                            })
+                        }
+                        "StartStream" => Vec::new(),
+                        _ => {
+                            self.0 += 1;
+                            ::encoding::encode_uint64(self.0)
+                        }
+                    };
+                    results.push(::schema::ProcedureResult { error: None, value });
+                    procedures.push(call.procedure);
+                }
+                self.1.push(procedures);
+
+                Box::new(::futures::future::ok((
+                    ::schema::Response {
+                        error: None,
+                        results,
+                    },
+                    self,
+                )))
+            }
+        }
+
+        let expression = Expression::call(krpc::GetStatus).equal(true);
+
+        let server = Server::new(MockConnection(0, Vec::new()));
+        let (event, server) = server.add_event(expression).wait().unwrap();
+        assert_eq!(4, event.id());
+
+        let streams = server.streams().clone();
+        let MockConnection(_, requests) = server.into_inner();
+        assert_eq!(
+            vec![
+                vec!["Expression_static_Call", "Expression_static_ConstantBool"],
+                vec!["Expression_static_Equal"],
+                vec!["AddEvent"],
+                vec!["StartStream"],
+            ],
+            requests
+        );
+
+        let updates = vec![false, true]
+            .into_iter()
+            .map(|fired| ::schema::StreamUpdate {
+                results: vec![::schema::StreamResult {
+                    id: 4,
+                    result: Some(::schema::ProcedureResult {
+                        error: None,
+                        value: ::encoding::encode_bool(fired),
+                    }),
+                }],
+            })
+            .collect::<Vec<_>>();
+        streams
+            .dispatch(::futures::stream::iter_ok::<_, ConnectionError>(updates))
+            .wait()
+            .unwrap();
+
+        event.wait().unwrap();
+    }
+
+    fn extract_krpc_call(mut request: ::schema::Request) -> ::schema::ProcedureCall {
+        assert_eq!(1, request.calls.len());
+        let call = request.calls.pop().unwrap();
+
+        assert_eq!("KRPC", call.service);
+
+        call
+    }
+
+    fn extract_call(mut request: ::schema::Request) -> ::schema::ProcedureCall {
+        assert_eq!(1, request.calls.len());
+        let call = request.calls.pop().unwrap();
+
+        assert_eq!(MOCK_SERVICE, call.service);
+        assert_eq!(MOCK_PROCEDURE, call.procedure);
+
+        call
+    }
+}
@@ -0,0 +1,111 @@
+#[cfg(feature = "async")]
+mod handle;
+
+#[cfg(feature = "async")]
+pub use self::handle::{BatchError, DynamicCallError, ProcedureCallError, Server};
+
+use std::fmt::{Debug, Display};
+
+use schema;
+use services::Exception;
+
+pub trait ProcedureCall: Into<schema::ProcedureCall> + 'static {
+    type Result: FromProcedureResult;
+    type Error: ::failure::Fail
+        + From<schema::Error>
+        + From<<Self::Result as FromProcedureResult>::Error>;
+}
+
+#[derive(Debug, Fail)]
+pub enum SimpleResultError {
+    #[fail(display = "Server returned error: {}", _0)]
+    Server(Exception),
+    #[fail(display = "Error decoding results: {}", _0)]
+    Decode(::prost::DecodeError),
+}
+
+impl From<schema::Error> for SimpleResultError {
+    fn from(err: schema::Error) -> Self {
+        SimpleResultError::Server(err.into())
+    }
+}
+
+impl From<::prost::DecodeError> for SimpleResultError {
+    fn from(e: ::prost::DecodeError) -> Self {
+        SimpleResultError::Decode(e)
+    }
+}
+
+pub trait FromProcedureResult
+where
+    Self: ::std::marker::Sized,
+{
+    type Error: Debug;
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error>;
+}
+
+/// A protobuf message that is sent as a procedure result as it is, like the
+/// messages in `schema`. Implementing it for a `prost` message makes it
+/// decodable as a result.
+///
+/// This takes the place of an impl for every `prost::Message`, which would
+/// also cover the numbers, strings and collections `prost` implements
+/// `Message` for. kRPC encodes those without a field tag, see `encoding`.
+pub trait MessageResult: ::prost::Message + Default {}
+
+impl<M: MessageResult> FromProcedureResult for M {
+    type Error = ::prost::DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, ::prost::DecodeError> {
+        Self::decode(value)
+    }
+}
+
+/// Encodes a procedure argument in kRPC's wire format. The counterpart of
+/// `FromProcedureResult`.
+pub trait EncodeArgument {
+    fn encode_argument(&self) -> Vec<u8>;
+
+    fn to_argument(&self, position: u32) -> schema::Argument {
+        schema::Argument {
+            position,
+            value: self.encode_argument(),
+        }
+    }
+}
+
+impl ::failure::Fail for schema::Error {}
+
+impl Display for schema::Error {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        f.write_fmt(format_args!("{}", self.description))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exception_from_error() {
+        use services::krpc;
+
+        let error = |service: &str, name: &str| schema::Error {
+            service: service.to_string(),
+            name: name.to_string(),
+            description: "Vessel is not the active vessel".to_string(),
+            stack_trace: "at KRPC.SpaceCenter.Services.Vessel".to_string(),
+        };
+
+        match SimpleResultError::from(error("KRPC", "InvalidOperationException")) {
+            SimpleResultError::Server(Exception::Krpc(
+                krpc::Exception::InvalidOperationException(e),
+            )) => assert_eq!("at KRPC.SpaceCenter.Services.Vessel", e.stack_trace),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        assert_eq!(
+            Exception::Other(error("SpaceCenter", "InvalidOperationException")),
+            Exception::from(error("SpaceCenter", "InvalidOperationException"))
+        );
+    }
+}
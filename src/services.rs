@@ -0,0 +1,14 @@
+//! Typed bindings for the services exposed by the kRPC server.
+//!
+//! Generated by `build.rs` from `services.json`. Every service is a module
+//! with a marker type per class, an enum per enumeration and a
+//! `ProcedureCall` per procedure, named after the procedure in CamelCase
+//! (`SpaceCenter.Vessel_get_Name` is `space_center::VesselGetName`). Objects
+//! are passed around as `RemoteObject<space_center::Vessel>` and friends.
+//!
+//! Server errors are sorted into `Exception`, with one variant per service
+//! that declares exceptions, e.g. `Exception::Krpc(krpc::Exception::..)`.
+
+#![allow(dead_code)]
+
+include!(concat!(env!("OUT_DIR"), "/services.rs"));
@@ -0,0 +1,685 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Display};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use futures::sync::mpsc;
+use futures::task::{self, Task};
+use prost::DecodeError;
+
+use connection::ConnectionError;
+use encoding;
+use expression::Expression;
+use schema;
+use server::FromProcedureResult;
+use services::krpc;
+
+/// Routes the results of a stream connection to the `TypedStream`s that were
+/// registered for them.
+///
+/// Cloning yields another handle to the same set of streams.
+#[derive(Debug, Clone, Default)]
+pub struct Streams {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    // Keyed by a local id that, unlike the server's stream id, stays the same
+    // when a stream is added again after a reconnect.
+    streams: HashMap<u64, Registered>,
+    // The server hands out the same id when the same call is added twice, so
+    // one server stream can have several subscribers.
+    keys: HashMap<u64, HashSet<u64>>,
+    next_key: u64,
+    // The server may push a result before `AddStream` has returned its id,
+    // so the latest result of every unknown stream is kept around.
+    pending: HashMap<u64, schema::ProcedureResult>,
+    /// Dropped streams that still have to be removed on the server.
+    removed: Vec<u64>,
+    /// Dropped streams the server hasn't confirmed the removal of yet. Their
+    /// updates are thrown away.
+    tombstones: HashSet<u64>,
+    /// The task that removes dropped streams right away, see `poll_removed`.
+    remover: Option<Task>,
+}
+
+#[derive(Debug)]
+struct Registered {
+    /// The id of the stream on the server. Outdated while detached.
+    id: u64,
+    attached: bool,
+    sender: mpsc::UnboundedSender<schema::ProcedureResult>,
+    registration: Registration,
+}
+
+/// What was sent to the server to create a stream, so it can be created again
+/// on a new connection.
+#[derive(Debug, Clone)]
+pub(crate) enum Registration {
+    Stream(schema::ProcedureCall),
+    Event(Expression),
+}
+
+impl Registry {
+    fn send(&mut self, id: u64, value: schema::ProcedureResult) {
+        if self.tombstones.contains(&id) {
+            return;
+        }
+
+        let keys = match self.keys.get(&id) {
+            Some(keys) => keys.clone(),
+            None => HashSet::new(),
+        };
+        for key in keys {
+            let sent = match self.streams.get(&key) {
+                Some(registered) => registered.sender.unbounded_send(value.clone()).is_ok(),
+                None => false,
+            };
+            if !sent {
+                self.streams.remove(&key);
+                self.unsubscribe(id, key);
+            }
+        }
+
+        if !self.keys.contains_key(&id) {
+            self.pending.insert(id, value);
+        }
+    }
+
+    fn subscribe(&mut self, id: u64, key: u64) {
+        self.keys.entry(id).or_insert_with(HashSet::new).insert(key);
+        self.tombstones.remove(&id);
+        if let Some(result) = self.pending.remove(&id) {
+            self.send(id, result);
+        }
+    }
+
+    /// Takes `key` off the subscribers of the server stream `id`. Returns
+    /// whether it was the last one.
+    fn unsubscribe(&mut self, id: u64, key: u64) -> bool {
+        let last = match self.keys.get_mut(&id) {
+            Some(keys) => {
+                keys.remove(&key);
+                keys.is_empty()
+            }
+            None => return false,
+        };
+
+        if last {
+            self.keys.remove(&id);
+        }
+        last
+    }
+
+    fn remove_on_server(&mut self, id: u64) {
+        self.removed.push(id);
+        self.tombstones.insert(id);
+        if let Some(task) = self.remover.take() {
+            task.notify();
+        }
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns a future feeding the updates of `updates` (usually a
+    /// `StreamConnection`) to the registered streams. It has to be spawned for
+    /// any `TypedStream` to make progress.
+    pub fn dispatch<S>(&self, updates: S) -> StreamDispatch<S>
+    where
+        S: Stream<Item = schema::StreamUpdate, Error = ConnectionError>,
+    {
+        StreamDispatch {
+            updates,
+            streams: self.clone(),
+        }
+    }
+
+    pub(crate) fn register<T>(&self, id: u64, registration: Registration) -> TypedStream<T> {
+        TypedStream {
+            subscription: self.subscribe(id, registration),
+            _result: PhantomData,
+        }
+    }
+
+    pub(crate) fn subscribe(&self, id: u64, registration: Registration) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded();
+
+        let mut registry = self.inner.lock().unwrap();
+        let key = registry.next_key;
+        registry.next_key += 1;
+
+        registry.streams.insert(
+            key,
+            Registered {
+                id,
+                attached: true,
+                sender,
+                registration,
+            },
+        );
+        // Can't lose a pending result, the receiver is right here
+        registry.subscribe(id, key);
+
+        Subscription {
+            key,
+            id,
+            receiver,
+            streams: self.clone(),
+        }
+    }
+
+    /// Builds the request for `calls`. Streams dropped since the last request
+    /// are removed as part of it. Their calls come first, so their ids are
+    /// returned alongside, to be passed on to `removal_answered` or
+    /// `removal_failed`.
+    pub(crate) fn request(&self, calls: Vec<schema::ProcedureCall>) -> (schema::Request, Vec<u64>) {
+        let removed = self.take_removed();
+        let mut all_calls = removal_calls(&removed);
+        all_calls.extend(calls);
+
+        (schema::Request { calls: all_calls }, removed)
+    }
+
+    /// Takes the ids of all dropped streams that still have to be removed on
+    /// the server.
+    pub(crate) fn take_removed(&self) -> Vec<u64> {
+        let mut registry = self.inner.lock().unwrap();
+        ::std::mem::replace(&mut registry.removed, Vec::new())
+    }
+
+    /// Like `take_removed`, but if there is nothing to remove the current task
+    /// is woken once a stream is dropped.
+    pub(crate) fn poll_removed(&self) -> Async<Vec<u64>> {
+        let mut registry = self.inner.lock().unwrap();
+        if registry.removed.is_empty() {
+            registry.remover = Some(task::current());
+            return Async::NotReady;
+        }
+        Async::Ready(::std::mem::replace(&mut registry.removed, Vec::new()))
+    }
+
+    /// Forgets the streams `removed` once the server answered the request that
+    /// removes them.
+    pub(crate) fn removal_answered(&self, removed: Vec<u64>, response: &schema::Response) {
+        if response.error.is_some() {
+            self.removal_failed(removed);
+            return;
+        }
+
+        let mut registry = self.inner.lock().unwrap();
+        for id in removed {
+            registry.tombstones.remove(&id);
+            registry.pending.remove(&id);
+        }
+    }
+
+    /// Removes the streams `removed` again with a later request, after the
+    /// one that should have removed them failed.
+    pub(crate) fn removal_failed(&self, removed: Vec<u64>) {
+        let mut registry = self.inner.lock().unwrap();
+        for id in removed {
+            // Unless they belong to a connection that is gone by now
+            if registry.tombstones.contains(&id) {
+                registry.remove_on_server(id);
+            }
+        }
+    }
+
+    /// Forgets the server side of every stream after the connection was lost.
+    /// The streams stay registered until they are attached to their
+    /// counterpart on the next connection.
+    pub(crate) fn detach(&self) {
+        let mut registry = self.inner.lock().unwrap();
+        registry.keys.clear();
+        registry.pending.clear();
+        registry.removed.clear();
+        registry.tombstones.clear();
+        for registered in registry.streams.values_mut() {
+            registered.attached = false;
+        }
+    }
+
+    /// The streams that have to be added again by their local key, in the
+    /// order they were first added.
+    pub(crate) fn detached(&self) -> Vec<(u64, Registration)> {
+        let registry = self.inner.lock().unwrap();
+        let mut detached: Vec<_> = registry
+            .streams
+            .iter()
+            .filter(|&(_, registered)| !registered.attached)
+            .map(|(&key, registered)| (key, registered.registration.clone()))
+            .collect();
+        detached.sort_by_key(|&(key, _)| key);
+        detached
+    }
+
+    /// Routes the results of the server's stream `id` to the stream `key`.
+    pub(crate) fn attach(&self, key: u64, id: u64) {
+        let mut registry = self.inner.lock().unwrap();
+        match registry.streams.get_mut(&key) {
+            Some(registered) => {
+                registered.id = id;
+                registered.attached = true;
+            }
+            // Dropped in the meantime
+            None => {
+                if !registry.keys.contains_key(&id) {
+                    registry.remove_on_server(id);
+                }
+                return;
+            }
+        }
+
+        registry.subscribe(id, key);
+    }
+
+    fn id(&self, key: u64) -> Option<u64> {
+        let registry = self.inner.lock().unwrap();
+        registry.streams.get(&key).map(|registered| registered.id)
+    }
+
+    /// Removes the stream `key`. The server stream ends with its last
+    /// subscriber.
+    pub(crate) fn remove(&self, key: u64) {
+        let mut registry = self.inner.lock().unwrap();
+        let registered = match registry.streams.remove(&key) {
+            Some(registered) => registered,
+            None => return,
+        };
+
+        if registered.attached && registry.unsubscribe(registered.id, key) {
+            registry.pending.remove(&registered.id);
+            registry.remove_on_server(registered.id);
+        }
+    }
+
+    pub(crate) fn deliver(&self, update: schema::StreamUpdate) {
+        let mut registry = self.inner.lock().unwrap();
+
+        for result in update.results {
+            if let Some(value) = result.result {
+                registry.send(result.id, value);
+            }
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        let mut registry = self.inner.lock().unwrap();
+        registry.streams.clear();
+        registry.keys.clear();
+    }
+}
+
+/// The calls that remove the streams `ids` on the server.
+pub(crate) fn removal_calls(ids: &[u64]) -> Vec<schema::ProcedureCall> {
+    ids.iter().map(|&id| krpc::RemoveStream { id }.into()).collect()
+}
+
+/// Future returned by `Streams::dispatch`.
+///
+/// It completes when the underlying stream connection is closed, which also
+/// ends every registered `TypedStream`.
+#[derive(Debug)]
+pub struct StreamDispatch<S> {
+    updates: S,
+    streams: Streams,
+}
+
+impl<S> Future for StreamDispatch<S>
+where
+    S: Stream<Item = schema::StreamUpdate, Error = ConnectionError>,
+{
+    type Item = ();
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Result<Async<()>, ConnectionError> {
+        loop {
+            let update = match self.updates.poll() {
+                Ok(Async::Ready(Some(update))) => update,
+                Ok(Async::Ready(None)) => {
+                    self.streams.close();
+                    return Ok(Async::Ready(()));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.streams.close();
+                    return Err(e);
+                }
+            };
+
+            self.streams.deliver(update);
+        }
+    }
+}
+
+/// The results received for a single stream. Dropping its last subscription
+/// removes the stream on the server, with the next request or right away if a
+/// `ReconnectTask` is running.
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    key: u64,
+    /// The id the stream was created with.
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<schema::ProcedureResult>,
+    streams: Streams,
+}
+
+impl Subscription {
+    /// The current id of the stream on the server, which changes when it is
+    /// added again after a reconnect.
+    fn id(&self) -> u64 {
+        self.streams.id(self.key).unwrap_or(self.id)
+    }
+
+    fn poll_result(&mut self) -> Async<Option<schema::ProcedureResult>> {
+        match self.receiver.poll() {
+            Ok(v) => v,
+            Err(()) => unreachable!("receiving from an unbounded channel can't fail"),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.streams.remove(self.key);
+    }
+}
+
+/// The values of a procedure call that the server evaluates continuously.
+///
+/// Created by `Server::add_stream`. Adding the same call again shares the
+/// stream on the server, which is removed once every `TypedStream` of it is
+/// dropped: with the next request sent through the same `Server`, or right away
+/// if a `ReconnectTask` is running.
+pub struct TypedStream<T> {
+    subscription: Subscription,
+    _result: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedStream<T> {
+    pub fn id(&self) -> u64 {
+        self.subscription.id()
+    }
+
+    /// The call that limits this stream to `rate` updates per second.
+    pub fn set_rate(&self, rate: f32) -> krpc::SetStreamRate {
+        krpc::SetStreamRate {
+            id: self.id(),
+            rate,
+        }
+    }
+}
+
+impl<T> Debug for TypedStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("TypedStream").field("id", &self.id()).finish()
+    }
+}
+
+impl<T: FromProcedureResult> Stream for TypedStream<T> {
+    type Item = T;
+    type Error = StreamError<T::Error>;
+
+    fn poll(&mut self) -> Result<Async<Option<T>>, StreamError<T::Error>> {
+        let result = match self.subscription.poll_result() {
+            Async::Ready(Some(result)) => result,
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        if let Some(e) = result.error {
+            return Err(StreamError::Server(e));
+        }
+
+        let value = T::try_from(result.value).map_err(StreamError::Decode)?;
+        Ok(Async::Ready(Some(value)))
+    }
+}
+
+/// Resolves once the expression of a server side event becomes true.
+///
+/// Created by `Server::add_event`. Like a `TypedStream`, dropping it removes
+/// the underlying stream on the server.
+#[derive(Debug)]
+pub struct Event {
+    subscription: Subscription,
+}
+
+impl Event {
+    pub(crate) fn new(subscription: Subscription) -> Self {
+        Event { subscription }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.subscription.id()
+    }
+}
+
+impl Future for Event {
+    type Item = ();
+    type Error = StreamError<DecodeError>;
+
+    fn poll(&mut self) -> Result<Async<()>, StreamError<DecodeError>> {
+        loop {
+            let result = match self.subscription.poll_result() {
+                Async::Ready(Some(result)) => result,
+                Async::Ready(None) => return Err(StreamError::Closed),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            if let Some(e) = result.error {
+                return Err(StreamError::Server(e));
+            }
+
+            if encoding::decode_bool(&result.value).map_err(StreamError::Decode)? {
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamError<E> {
+    Server(schema::Error),
+    Decode(E),
+    Closed,
+}
+
+impl<E: Debug> Display for StreamError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            StreamError::Server(ref e) => write!(f, "Server returned error: {}", e),
+            StreamError::Decode(ref e) => write!(f, "Error decoding stream value: {:?}", e),
+            StreamError::Closed => write!(f, "Stream connection closed"),
+        }
+    }
+}
+
+impl<E> ::failure::Fail for StreamError<E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    fn cause(&self) -> Option<&::failure::Fail> {
+        match *self {
+            StreamError::Server(ref e) => Some(e),
+            StreamError::Decode(_) | StreamError::Closed => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration() -> Registration {
+        Registration::Stream(encoding::procedure_call("KRPC", "GetStatus", Vec::new()))
+    }
+
+    fn update(id: u64, value: Vec<u8>) -> schema::StreamUpdate {
+        schema::StreamUpdate {
+            results: vec![schema::StreamResult {
+                id,
+                result: Some(schema::ProcedureResult { error: None, value }),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_dispatch_routes_by_id() {
+        let streams = Streams::new();
+
+        let first = streams.register::<schema::Stream>(1, registration());
+        let second = streams.register::<schema::Stream>(2, registration());
+
+        let updates = vec![
+            update(2, encoding::encode_message(&schema::Stream { id: 20 })),
+            update(1, encoding::encode_message(&schema::Stream { id: 10 })),
+            update(2, encoding::encode_message(&schema::Stream { id: 21 })),
+        ];
+
+        streams
+            .dispatch(::futures::stream::iter_ok::<_, ConnectionError>(updates))
+            .wait()
+            .unwrap();
+
+        let first = first.map(|s| s.id).collect().wait().unwrap();
+        let second = second.map(|s| s.id).collect().wait().unwrap();
+
+        assert_eq!(vec![10], first);
+        assert_eq!(vec![20, 21], second);
+    }
+
+    #[test]
+    fn test_early_result_is_kept() {
+        let streams = Streams::new();
+
+        let updates = vec![update(
+            3,
+            encoding::encode_message(&schema::Stream { id: 30 }),
+        )];
+        streams
+            .dispatch(::futures::stream::iter_ok::<_, ConnectionError>(updates))
+            .wait()
+            .unwrap();
+
+        let stream = streams.register::<schema::Stream>(3, registration());
+        assert_eq!(30, stream.wait().next().unwrap().unwrap().id);
+    }
+
+    #[test]
+    fn test_drop_marks_removed() {
+        let streams = Streams::new();
+
+        let stream = streams.register::<()>(5, registration());
+        assert!(streams.take_removed().is_empty());
+
+        drop(stream);
+        assert_eq!(vec![5], streams.take_removed());
+        assert!(streams.take_removed().is_empty());
+    }
+
+    #[test]
+    fn test_shared_stream() {
+        let streams = Streams::new();
+
+        let first = streams.register::<schema::Stream>(4, registration());
+        let second = streams.register::<schema::Stream>(4, registration());
+        streams.deliver(update(4, encoding::encode_message(&schema::Stream { id: 40 })));
+
+        drop(first);
+        assert!(streams.take_removed().is_empty());
+
+        streams.deliver(update(4, encoding::encode_message(&schema::Stream { id: 41 })));
+        let mut second = second.wait();
+        assert_eq!(40, second.next().unwrap().unwrap().id);
+        assert_eq!(41, second.next().unwrap().unwrap().id);
+
+        drop(second);
+        assert_eq!(vec![4], streams.take_removed());
+    }
+
+    #[test]
+    fn test_removed_stream_updates_are_dropped() {
+        let streams = Streams::new();
+        let pending = |streams: &Streams| streams.inner.lock().unwrap().pending.len();
+
+        drop(streams.register::<()>(5, registration()));
+        streams.deliver(update(5, vec![1]));
+        assert_eq!(0, pending(&streams));
+
+        // The request that removes it fails, so it is removed again
+        let (request, removed) = streams.request(Vec::new());
+        assert_eq!(1, request.calls.len());
+        streams.removal_failed(removed);
+        let (_, removed) = streams.request(Vec::new());
+        assert_eq!(vec![5], removed);
+
+        streams.deliver(update(5, vec![2]));
+        assert_eq!(0, pending(&streams));
+
+        streams.removal_answered(removed, &schema::Response::default());
+        assert!(streams.inner.lock().unwrap().tombstones.is_empty());
+        assert!(streams.take_removed().is_empty());
+    }
+
+    #[test]
+    fn test_procedure_error() {
+        let streams = Streams::new();
+        let stream = streams.register::<()>(1, registration());
+
+        let error = schema::Error {
+            service: "KRPC".to_string(),
+            name: "InvalidOperationException".to_string(),
+            description: "No vessel".to_string(),
+            stack_trace: String::new(),
+        };
+        let updates = vec![schema::StreamUpdate {
+            results: vec![schema::StreamResult {
+                id: 1,
+                result: Some(schema::ProcedureResult {
+                    error: Some(error.clone()),
+                    value: Vec::new(),
+                }),
+            }],
+        }];
+        streams
+            .dispatch(::futures::stream::iter_ok::<_, ConnectionError>(updates))
+            .wait()
+            .unwrap();
+
+        match stream.wait().next() {
+            Some(Err(StreamError::Server(e))) => assert_eq!(error, e),
+            other => panic!("Unexpected stream item: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reattach_after_reconnect() {
+        let streams = Streams::new();
+        let stream = streams.register::<schema::Stream>(1, registration());
+
+        streams.detach();
+        // A late update of the old connection is not for this stream
+        streams.deliver(update(1, encoding::encode_message(&schema::Stream { id: 10 })));
+
+        let detached = streams.detached();
+        assert_eq!(1, detached.len());
+        match detached[0].1 {
+            Registration::Stream(ref call) => assert_eq!("GetStatus", call.procedure),
+            ref other => panic!("Unexpected registration: {:?}", other),
+        }
+
+        streams.attach(detached[0].0, 7);
+        assert_eq!(7, stream.id());
+        assert!(streams.detached().is_empty());
+
+        streams.deliver(update(7, encoding::encode_message(&schema::Stream { id: 70 })));
+        assert_eq!(70, stream.wait().next().unwrap().unwrap().id);
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt::{self, Debug};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
+use futures::prelude::*;
+
+use client::{decode_result, ClientError};
+use connection::{BlockingConnection, ConnectionBuilder, ConnectionError};
+use discovery::{ProcedureIds, ServiceTree};
+use schema;
+use server::{FromProcedureResult, ProcedureCall};
+use services::krpc;
+use stream::{Registration, StreamError, Streams, TypedStream};
+
+/// A client that blocks the calling thread until each call is answered, for
+/// scripts that have no use for futures. Unlike `Server`, it doesn't need the
+/// `async` feature and builds on stable Rust.
+///
+/// Stream updates are received on a background thread, which ends when the
+/// client is dropped.
+pub struct SyncClient {
+    connection: BlockingConnection<TcpStream>,
+    streams: Streams,
+    /// The socket of the stream connection, to shut it down on drop.
+    updates: TcpStream,
+    procedure_ids: Option<ProcedureIds>,
+}
+
+impl SyncClient {
+    /// Connects with the default settings of `ConnectionBuilder`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ConnectionError> {
+        Self::connect_with(ConnectionBuilder::new(), addr)
+    }
+
+    /// Opens the RPC and the stream connection to the server at `addr`.
+    pub fn connect_with<A: ToSocketAddrs>(
+        builder: ConnectionBuilder,
+        addr: A,
+    ) -> Result<Self, ConnectionError> {
+        let tcp = TcpStream::connect(addr)?;
+        let addr = tcp.peer_addr()?;
+        let connection = builder.clone().initialize_blocking(tcp)?;
+
+        let tcp = TcpStream::connect(addr)?;
+        let updates = tcp.try_clone()?;
+        let client_identifier = connection.client_identifier().to_vec();
+        let stream_connection = builder.initialize_blocking_stream(tcp, client_identifier)?;
+
+        let streams = Streams::new();
+        {
+            let streams = streams.clone();
+            thread::spawn(move || receive_updates(stream_connection, streams));
+        }
+
+        Ok(SyncClient {
+            connection,
+            streams,
+            updates,
+            procedure_ids: None,
+        })
+    }
+
+    /// The identifier the server assigned to this client.
+    pub fn client_identifier(&self) -> &[u8] {
+        self.connection.client_identifier()
+    }
+
+    /// Sends calls with the ids of their procedures in `services` instead of
+    /// their names. Procedures that are not in `services` are still called by
+    /// name.
+    pub fn resolve_calls(&mut self, services: &ServiceTree) {
+        self.procedure_ids = Some(ProcedureIds::new(services));
+    }
+
+    /// Calls `p` and waits for its result.
+    pub fn call<P: ProcedureCall>(&mut self, p: P) -> Result<P::Result, ClientError<P>> {
+        let (request, removed) = self.streams.request(vec![p.into()]);
+        let skip = removed.len();
+
+        let response = match self.request(request) {
+            Ok(response) => response,
+            Err(e) => {
+                self.streams.removal_failed(removed);
+                return Err(ClientError::Connection(e));
+            }
+        };
+        self.streams.removal_answered(removed, &response);
+        decode_result(response, skip)
+    }
+
+    /// Sends `request` and waits for the response.
+    pub fn request(
+        &mut self,
+        mut request: schema::Request,
+    ) -> Result<schema::Response, ConnectionError> {
+        if let Some(ref ids) = self.procedure_ids {
+            ids.resolve_request(&mut request);
+        }
+
+        self.connection.send(&request)?;
+        self.connection.receive()
+    }
+
+    /// Registers `p` as a stream on the server and starts it.
+    pub fn add_stream<P: ProcedureCall>(
+        &mut self,
+        p: P,
+    ) -> Result<SyncStream<P::Result>, ClientError<krpc::AddStream>> {
+        let call: schema::ProcedureCall = p.into();
+        let stream = self.call(krpc::AddStream {
+            call: call.clone(),
+            start: true,
+        })?;
+
+        let stream = self.streams.register(stream.id, Registration::Stream(call));
+        Ok(SyncStream {
+            inner: executor::spawn(stream),
+        })
+    }
+}
+
+impl Debug for SyncClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("SyncClient")
+            .field("client_identifier", &self.client_identifier())
+            .finish()
+    }
+}
+
+impl Drop for SyncClient {
+    fn drop(&mut self) {
+        // Ends the receiving thread
+        let _ = self.updates.shutdown(Shutdown::Both);
+    }
+}
+
+fn receive_updates(mut connection: BlockingConnection<TcpStream>, streams: Streams) {
+    while let Ok(update) = connection.receive::<schema::StreamUpdate>() {
+        streams.deliver(update);
+    }
+    streams.close();
+}
+
+/// The values of a stream added through a `SyncClient`.
+///
+/// Iterating waits for every single value, `latest` skips to the most recent
+/// one without waiting. Dropping it removes the stream on the server with the
+/// next call.
+pub struct SyncStream<T> {
+    inner: Spawn<TypedStream<T>>,
+}
+
+impl<T: FromProcedureResult> SyncStream<T> {
+    pub fn id(&self) -> u64 {
+        self.inner.get_ref().id()
+    }
+
+    /// The most recent value received since the last call, or `None` if
+    /// nothing arrived in the meantime.
+    pub fn latest(&mut self) -> Result<Option<T>, StreamError<T::Error>> {
+        let notify = NotifyHandle::from(Arc::new(NoNotify));
+
+        let mut latest = None;
+        while let Async::Ready(Some(value)) = self.inner.poll_stream_notify(&notify, 0)? {
+            latest = Some(value);
+        }
+
+        Ok(latest)
+    }
+}
+
+impl<T: FromProcedureResult> Iterator for SyncStream<T> {
+    type Item = Result<T, StreamError<T::Error>>;
+
+    /// Waits for the next value. Ends when the stream connection is closed.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.wait_stream()
+    }
+}
+
+impl<T> Debug for SyncStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("SyncStream")
+            .field("id", &self.inner.get_ref().id())
+            .finish()
+    }
+}
+
+/// Polling without waiting needs no wakeups.
+struct NoNotify;
+
+impl Notify for NoNotify {
+    fn notify(&self, _: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    use encoding;
+
+    /// Accepts a connection and its handshake.
+    fn accept(
+        listener: &TcpListener,
+    ) -> (BlockingConnection<TcpStream>, schema::ConnectionRequest) {
+        let (socket, _) = listener.accept().unwrap();
+        let mut connection = BlockingConnection::new(socket);
+
+        let request = connection.receive().unwrap();
+        connection
+            .send(&schema::ConnectionResponse {
+                status: schema::connection_response::Status::Ok.into(),
+                message: String::new(),
+                client_identifier: vec![3],
+            })
+            .unwrap();
+
+        (connection, request)
+    }
+
+    fn respond(connection: &mut BlockingConnection<TcpStream>, procedure: &str, value: Vec<u8>) {
+        let request: schema::Request = connection.receive().unwrap();
+        assert_eq!(procedure, request.calls[0].procedure);
+
+        connection
+            .send(&schema::Response {
+                error: None,
+                results: vec![schema::ProcedureResult { error: None, value }],
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_call_and_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let status = schema::Status {
+            version: "0.4.8".to_string(),
+            ..Default::default()
+        };
+
+        let server = {
+            let status = encoding::encode_message(&status);
+            thread::spawn(move || {
+                let (mut rpc, request) = accept(&listener);
+                assert_eq!("Mission script", request.client_name);
+                let (mut updates, request) = accept(&listener);
+                assert_eq!(vec![3], request.client_identifier);
+
+                let stream = encoding::encode_message(&schema::Stream { id: 7 });
+                respond(&mut rpc, "AddStream", stream);
+                updates
+                    .send(&schema::StreamUpdate {
+                        results: vec![schema::StreamResult {
+                            id: 7,
+                            result: Some(schema::ProcedureResult {
+                                error: None,
+                                value: status.clone(),
+                            }),
+                        }],
+                    })
+                    .unwrap();
+
+                respond(&mut rpc, "GetStatus", status);
+            })
+        };
+
+        let builder = ConnectionBuilder::new().client_name("Mission script");
+        let mut client = SyncClient::connect_with(builder, addr).unwrap();
+
+        let mut stream = client.add_stream(krpc::GetStatus).unwrap();
+        assert_eq!(7, stream.id());
+        assert_eq!(status, stream.next().unwrap().unwrap());
+        assert_eq!(None, stream.latest().unwrap());
+
+        assert_eq!(status, client.call(krpc::GetStatus).unwrap());
+        server.join().unwrap();
+    }
+}
@@ -1,6 +1,11 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
 use proptest::prelude::*;
 use proptest::strategy::ValueFor;
 use proptest::test_runner::{Config, TestCaseResult, TestError, TestRunner};
+use tokio_io::{AsyncRead, AsyncWrite};
 
 pub(crate) fn run_test<S: Strategy, F: Fn(&ValueFor<S>) -> TestCaseResult>(
     strategy: &S,
@@ -13,3 +18,48 @@ pub(crate) fn run_test<S: Strategy, F: Fn(&ValueFor<S>) -> TestCaseResult>(
     });
     runner.run(strategy, test)
 }
+
+/// In-memory transport for driving connections in tests. Reads are served from
+/// a fixed buffer and everything written is collected for later inspection.
+#[derive(Debug)]
+pub(crate) struct MockIo {
+    input: io::Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockIo {
+    pub(crate) fn new(input: Vec<u8>) -> Self {
+        MockIo {
+            input: io::Cursor::new(input),
+            output: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub(crate) fn written(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.output.clone()
+    }
+}
+
+impl Read for MockIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MockIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MockIo {}
+
+impl AsyncWrite for MockIo {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
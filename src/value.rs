@@ -0,0 +1,460 @@
+//! Procedure arguments and results whose types are only known at runtime.
+
+use prost::{DecodeError, Message};
+
+use discovery::ServiceTree;
+use encoding;
+use schema::{self, type_::TypeCode};
+use server::{FromProcedureResult, ProcedureCall, SimpleResultError};
+
+/// A value of any type kRPC can send, one variant per `TypeCode`. Which one
+/// is expected is told by the `schema::Type` of a parameter or result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Double(f64),
+    Float(f32),
+    Sint32(i32),
+    Sint64(i64),
+    Uint32(u32),
+    Uint64(u64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    /// The handle of a remote object, 0 being no object.
+    Class(u64),
+    /// The number of an enumeration value.
+    Enumeration(i32),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Set(Vec<Value>),
+    Dictionary(Vec<(Value, Value)>),
+    ProcedureCall(schema::ProcedureCall),
+    Stream(schema::Stream),
+    Status(schema::Status),
+    Services(schema::Services),
+    Event(schema::Event),
+}
+
+#[derive(Debug, Fail)]
+pub enum EncodeError {
+    #[fail(display = "expected {}, got {:?}", _0, _1)]
+    Mismatch(String, Value),
+    #[fail(display = "invalid type {}", _0)]
+    InvalidType(String),
+}
+
+impl Value {
+    /// Encodes the value in kRPC's wire format, failing unless it is of type
+    /// `t`.
+    pub fn encode(&self, t: &schema::Type) -> Result<Vec<u8>, EncodeError> {
+        let invalid = || EncodeError::InvalidType(type_name(t));
+        let mismatch = || EncodeError::Mismatch(type_name(t), self.clone());
+
+        let code = TypeCode::from_i32(t.code).ok_or_else(&invalid)?;
+
+        let value = match (code, self) {
+            (TypeCode::None, &Value::None) => Vec::new(),
+            (TypeCode::Double, &Value::Double(v)) => encoding::encode_double(v),
+            (TypeCode::Float, &Value::Float(v)) => encoding::encode_float(v),
+            (TypeCode::Sint32, &Value::Sint32(v)) => encoding::encode_sint32(v),
+            (TypeCode::Sint64, &Value::Sint64(v)) => encoding::encode_sint64(v),
+            (TypeCode::Uint32, &Value::Uint32(v)) => encoding::encode_uint64(v as u64),
+            (TypeCode::Uint64, &Value::Uint64(v)) => encoding::encode_uint64(v),
+            (TypeCode::Bool, &Value::Bool(v)) => encoding::encode_bool(v),
+            (TypeCode::String, &Value::String(ref v)) => encoding::encode_string(v),
+            (TypeCode::Bytes, &Value::Bytes(ref v)) => encoding::encode_bytes(v),
+            (TypeCode::Class, &Value::Class(v)) => encoding::encode_uint64(v),
+            (TypeCode::Enumeration, &Value::Enumeration(v)) => encoding::encode_sint32(v),
+            (TypeCode::Tuple, &Value::Tuple(ref items)) => {
+                if items.len() != t.types.len() {
+                    return Err(mismatch());
+                }
+                let items = t.types
+                    .iter()
+                    .zip(items)
+                    .map(|(t, item)| item.encode(t))
+                    .collect::<Result<_, _>>()?;
+                encoding::encode_message(&schema::Tuple { items })
+            }
+            (TypeCode::List, &Value::List(ref items)) => {
+                let element = t.types.get(0).ok_or_else(&invalid)?;
+                let items = items
+                    .iter()
+                    .map(|item| item.encode(element))
+                    .collect::<Result<_, _>>()?;
+                encoding::encode_message(&schema::List { items })
+            }
+            (TypeCode::Set, &Value::Set(ref items)) => {
+                let element = t.types.get(0).ok_or_else(&invalid)?;
+                let items = items
+                    .iter()
+                    .map(|item| item.encode(element))
+                    .collect::<Result<_, _>>()?;
+                encoding::encode_message(&schema::Set { items })
+            }
+            (TypeCode::Dictionary, &Value::Dictionary(ref entries)) => {
+                let (key_type, value_type) = match (t.types.get(0), t.types.get(1)) {
+                    (Some(key), Some(value)) => (key, value),
+                    _ => return Err(invalid()),
+                };
+                let entries = entries
+                    .iter()
+                    .map(|&(ref key, ref value)| {
+                        Ok(schema::DictionaryEntry {
+                            key: key.encode(key_type)?,
+                            value: value.encode(value_type)?,
+                        })
+                    })
+                    .collect::<Result<_, EncodeError>>()?;
+                encoding::encode_message(&schema::Dictionary { entries })
+            }
+            (TypeCode::ProcedureCall, &Value::ProcedureCall(ref v)) => encoding::encode_message(v),
+            (TypeCode::Stream, &Value::Stream(ref v)) => encoding::encode_message(v),
+            (TypeCode::Status, &Value::Status(ref v)) => encoding::encode_message(v),
+            (TypeCode::Services, &Value::Services(ref v)) => encoding::encode_message(v),
+            (TypeCode::Event, &Value::Event(ref v)) => encoding::encode_message(v),
+            _ => return Err(mismatch()),
+        };
+
+        Ok(value)
+    }
+
+    /// Decodes a value of type `t` from kRPC's wire format.
+    pub fn decode(t: &schema::Type, value: &[u8]) -> Result<Value, DecodeError> {
+        let code = match TypeCode::from_i32(t.code) {
+            Some(code) => code,
+            None => return Err(DecodeError::new(format!("unknown type code {}", t.code))),
+        };
+
+        let value = match code {
+            TypeCode::None => Value::None,
+            TypeCode::Double => Value::Double(encoding::decode_double(value)?),
+            TypeCode::Float => Value::Float(encoding::decode_float(value)?),
+            TypeCode::Sint32 => Value::Sint32(encoding::decode_sint32(value)?),
+            TypeCode::Sint64 => Value::Sint64(encoding::decode_sint64(value)?),
+            TypeCode::Uint32 => Value::Uint32(encoding::decode_uint32(value)?),
+            TypeCode::Uint64 => Value::Uint64(encoding::decode_uint64(value)?),
+            TypeCode::Bool => Value::Bool(encoding::decode_bool(value)?),
+            TypeCode::String => Value::String(encoding::decode_string(value)?),
+            TypeCode::Bytes => Value::Bytes(encoding::decode_bytes(value)?),
+            TypeCode::Class => Value::Class(encoding::decode_uint64(value)?),
+            TypeCode::Enumeration => Value::Enumeration(encoding::decode_sint32(value)?),
+            TypeCode::Tuple => {
+                let tuple = schema::Tuple::decode(value)?;
+                if tuple.items.len() != t.types.len() {
+                    return Err(DecodeError::new(format!(
+                        "expected a tuple of {} items, got {}",
+                        t.types.len(),
+                        tuple.items.len()
+                    )));
+                }
+                let items = t.types
+                    .iter()
+                    .zip(tuple.items)
+                    .map(|(t, item)| Value::decode(t, &item))
+                    .collect::<Result<_, _>>()?;
+                Value::Tuple(items)
+            }
+            TypeCode::List => Value::List(decode_items(t, schema::List::decode(value)?.items)?),
+            TypeCode::Set => Value::Set(decode_items(t, schema::Set::decode(value)?.items)?),
+            TypeCode::Dictionary => {
+                let (key_type, value_type) = (element_type(t, 0)?, element_type(t, 1)?);
+                let entries = schema::Dictionary::decode(value)?
+                    .entries
+                    .into_iter()
+                    .map(|entry| {
+                        Ok((
+                            Value::decode(key_type, &entry.key)?,
+                            Value::decode(value_type, &entry.value)?,
+                        ))
+                    })
+                    .collect::<Result<_, DecodeError>>()?;
+                Value::Dictionary(entries)
+            }
+            TypeCode::ProcedureCall => Value::ProcedureCall(schema::ProcedureCall::decode(value)?),
+            TypeCode::Stream => Value::Stream(schema::Stream::decode(value)?),
+            TypeCode::Status => Value::Status(schema::Status::decode(value)?),
+            TypeCode::Services => Value::Services(schema::Services::decode(value)?),
+            TypeCode::Event => Value::Event(schema::Event::decode(value)?),
+        };
+
+        Ok(value)
+    }
+}
+
+fn decode_items(t: &schema::Type, items: Vec<Vec<u8>>) -> Result<Vec<Value>, DecodeError> {
+    let element = element_type(t, 0)?;
+    items
+        .into_iter()
+        .map(|item| Value::decode(element, &item))
+        .collect()
+}
+
+fn element_type(t: &schema::Type, index: usize) -> Result<&schema::Type, DecodeError> {
+    t.types
+        .get(index)
+        .ok_or_else(|| DecodeError::new("collection type is missing element types"))
+}
+
+/// A readable name of `t`, like `SpaceCenter.Vessel` or `List(Double)`.
+pub fn type_name(t: &schema::Type) -> String {
+    let elements = || {
+        t.types
+            .iter()
+            .map(type_name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    match TypeCode::from_i32(t.code) {
+        Some(TypeCode::Class) | Some(TypeCode::Enumeration) => format!("{}.{}", t.service, t.name),
+        Some(code @ TypeCode::Tuple)
+        | Some(code @ TypeCode::List)
+        | Some(code @ TypeCode::Set)
+        | Some(code @ TypeCode::Dictionary) => format!("{:?}({})", code, elements()),
+        Some(code) => format!("{:?}", code),
+        None => format!("unknown type {}", t.code),
+    }
+}
+
+/// A call of a procedure looked up at runtime, with its arguments checked
+/// against the schema. Its result is left encoded, to be decoded with
+/// `return_type`.
+#[derive(Debug)]
+pub struct DynamicCall {
+    call: schema::ProcedureCall,
+    return_type: schema::Type,
+}
+
+impl DynamicCall {
+    /// Optional parameters may be left out at the end of `arguments`, the
+    /// server fills in their defaults.
+    pub fn new(
+        services: &ServiceTree,
+        service: &str,
+        procedure: &str,
+        arguments: &[Value],
+    ) -> Result<Self, InvalidCall> {
+        let p = services.procedure(service, procedure).ok_or_else(|| {
+            InvalidCall::UnknownProcedure(service.to_string(), procedure.to_string())
+        })?;
+
+        if arguments.len() > p.parameters.len() {
+            return Err(InvalidCall::TooManyArguments(p.parameters.len()));
+        }
+        if let Some(parameter) = p.parameters[arguments.len()..]
+            .iter()
+            .find(|parameter| parameter.default_value.is_empty())
+        {
+            return Err(InvalidCall::MissingArgument(parameter.name.clone()));
+        }
+
+        let arguments = p.parameters
+            .iter()
+            .zip(arguments)
+            .enumerate()
+            .map(|(position, (parameter, value))| {
+                let t = parameter.type_.clone().unwrap_or_default();
+                match value.encode(&t) {
+                    Ok(value) => Ok(schema::Argument {
+                        position: position as u32,
+                        value,
+                    }),
+                    Err(e) => Err(InvalidCall::InvalidArgument(parameter.name.clone(), e)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(DynamicCall {
+            call: schema::ProcedureCall {
+                service: service.to_string(),
+                procedure: procedure.to_string(),
+                arguments,
+                ..Default::default()
+            },
+            return_type: p.return_type.clone().unwrap_or_default(),
+        })
+    }
+
+    pub fn return_type(&self) -> &schema::Type {
+        &self.return_type
+    }
+}
+
+impl ProcedureCall for DynamicCall {
+    type Result = Encoded;
+    type Error = SimpleResultError;
+}
+
+impl From<DynamicCall> for schema::ProcedureCall {
+    fn from(call: DynamicCall) -> Self {
+        call.call
+    }
+}
+
+/// A result as the server sent it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoded(pub Vec<u8>);
+
+impl FromProcedureResult for Encoded {
+    type Error = DecodeError;
+    fn try_from(value: Vec<u8>) -> Result<Self, DecodeError> {
+        Ok(Encoded(value))
+    }
+}
+
+/// Why a `DynamicCall` can't be made, before anything is sent.
+#[derive(Debug, Fail)]
+pub enum InvalidCall {
+    #[fail(display = "unknown procedure {}.{}", _0, _1)]
+    UnknownProcedure(String, String),
+    #[fail(display = "missing argument {}", _0)]
+    MissingArgument(String),
+    #[fail(display = "too many arguments, expected at most {}", _0)]
+    TooManyArguments(usize),
+    #[fail(display = "invalid argument {}: {}", _0, _1)]
+    InvalidArgument(String, EncodeError),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn value_type(code: TypeCode) -> schema::Type {
+        schema::Type {
+            code: code as i32,
+            ..Default::default()
+        }
+    }
+
+    fn collection(code: TypeCode, types: Vec<schema::Type>) -> schema::Type {
+        schema::Type {
+            code: code as i32,
+            types,
+            ..Default::default()
+        }
+    }
+
+    fn services() -> ServiceTree {
+        let parameter = |name: &str, code: TypeCode, default_value: Vec<u8>| schema::Parameter {
+            name: name.to_string(),
+            type_: Some(value_type(code)),
+            default_value,
+        };
+
+        ServiceTree::new(schema::Services {
+            services: vec![schema::Service {
+                name: "SpaceCenter".to_string(),
+                procedures: vec![schema::Procedure {
+                    name: "WarpTo".to_string(),
+                    parameters: vec![
+                        parameter("ut", TypeCode::Double, Vec::new()),
+                        parameter("max_rails_rate", TypeCode::Float, encoding::encode_float(1e5)),
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let t = collection(
+            TypeCode::Dictionary,
+            vec![
+                value_type(TypeCode::String),
+                collection(
+                    TypeCode::Tuple,
+                    vec![
+                        value_type(TypeCode::Class),
+                        collection(TypeCode::List, vec![value_type(TypeCode::Sint32)]),
+                    ],
+                ),
+            ],
+        );
+        let value = Value::Dictionary(vec![(
+            Value::String("Kerbin".to_string()),
+            Value::Tuple(vec![
+                Value::Class(7),
+                Value::List(vec![Value::Sint32(-1), Value::Sint32(2)]),
+            ]),
+        )]);
+
+        let encoded = value.encode(&t).unwrap();
+        assert_eq!(value, Value::decode(&t, &encoded).unwrap());
+
+        let status = Value::Status(schema::Status {
+            version: "0.4.5".to_string(),
+            ..Default::default()
+        });
+        let t = value_type(TypeCode::Status);
+        assert_eq!(status, Value::decode(&t, &status.encode(&t).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_encode_mismatch() {
+        match Value::Sint32(1).encode(&value_type(TypeCode::Sint64)) {
+            Err(EncodeError::Mismatch(t, Value::Sint32(1))) => assert_eq!("Sint64", t),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let t = collection(
+            TypeCode::Tuple,
+            vec![value_type(TypeCode::Bool), value_type(TypeCode::Bool)],
+        );
+        match Value::Tuple(vec![Value::Bool(true)]).encode(&t) {
+            Err(EncodeError::Mismatch(..)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        match Value::List(Vec::new()).encode(&value_type(TypeCode::List)) {
+            Err(EncodeError::InvalidType(..)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_type_name() {
+        let sas_mode = schema::Type {
+            code: TypeCode::Enumeration as i32,
+            service: "SpaceCenter".to_string(),
+            name: "SASMode".to_string(),
+            types: Vec::new(),
+        };
+        let t = collection(TypeCode::List, vec![sas_mode]);
+        assert_eq!("List(SpaceCenter.SASMode)", type_name(&t));
+        assert_eq!("Double", type_name(&value_type(TypeCode::Double)));
+    }
+
+    #[test]
+    fn test_dynamic_call() {
+        let services = services();
+
+        let call = DynamicCall::new(&services, "SpaceCenter", "WarpTo", &[Value::Double(2.5)])
+            .unwrap();
+        assert_eq!(&value_type(TypeCode::None), call.return_type());
+        let call = schema::ProcedureCall::from(call);
+        assert_eq!(1, call.arguments.len());
+        assert_eq!(encoding::encode_double(2.5), call.arguments[0].value);
+
+        match DynamicCall::new(&services, "SpaceCenter", "WarpTo", &[]) {
+            Err(InvalidCall::MissingArgument(ref name)) if name == "ut" => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let arguments = [Value::Double(2.5), Value::Float(1.0), Value::Float(1.0)];
+        match DynamicCall::new(&services, "SpaceCenter", "WarpTo", &arguments) {
+            Err(InvalidCall::TooManyArguments(2)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match DynamicCall::new(&services, "SpaceCenter", "WarpTo", &[Value::Float(2.5)]) {
+            Err(InvalidCall::InvalidArgument(ref name, _)) if name == "ut" => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match DynamicCall::new(&services, "SpaceCenter", "Warp", &[]) {
+            Err(InvalidCall::UnknownProcedure(..)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}